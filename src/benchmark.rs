@@ -0,0 +1,389 @@
+//! A load test of the real handler path. Synthetic peers on loopback send `/param`
+//! traffic from the controller side and answer `/q/all_params` with bursts of
+//! normalized values from the instrument side, like Surge does. The report has the
+//! throughput, packet loss and latency distribution of each phase.
+
+use crate::{
+    config::Config,
+    handler::spawn_handler_on,
+    osc::{self, Packet, Receiver},
+    peer::PeerKind,
+};
+use rosc::OscType;
+use serde::Serialize;
+use std::{
+    io,
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// A phase ends when nothing arrived for this long.
+const IDLE: Duration = Duration::from_millis(500);
+
+/// Addresses the synthetic controller cycles through.
+const PARAMS: [&str; 4] = [
+    "/param/a/osc/1/pitch",
+    "/param/a/filter/1/cutoff",
+    "/param/a/amp/gain",
+    "/param/b/lfo/1/rate",
+];
+
+/// How much traffic to send. A rate of 0 sends as fast as possible.
+#[derive(Debug, Clone)]
+pub(crate) struct BenchmarkSettings {
+    pub extend: bool,
+    pub messages: usize,
+    pub rate: u32,
+    pub bursts: usize,
+    pub burst_size: usize,
+    pub json: PathBuf,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    extend: bool,
+    phases: Vec<PhaseReport>,
+}
+
+#[derive(Serialize, Debug)]
+struct PhaseReport {
+    name: &'static str,
+    sent: usize,
+    received: usize,
+    /// Messages that didn't arrive, including whole bursts for lost requests.
+    lost: usize,
+    lost_requests: usize,
+    loss_percent: f64,
+    duration_secs: f64,
+    messages_per_sec: f64,
+    latency_ms: Option<Distribution>,
+}
+
+#[derive(Serialize, Debug)]
+struct Distribution {
+    min: f64,
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+/// A synthetic peer and the port arcflash receives its packets on.
+struct SyntheticPeer {
+    socket: UdpSocket,
+    arcflash: String,
+}
+
+impl SyntheticPeer {
+    fn bind(arcflash_port: u16) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind("127.0.0.1:0")?,
+            arcflash: format!("127.0.0.1:{}", arcflash_port),
+        })
+    }
+
+    fn send(&self, message: osc::Message) -> io::Result<()> {
+        let bytes = osc::encode(Packet::Message(message))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        self.socket.send_to(&bytes, &self.arcflash).map(|_| ())
+    }
+
+    /// Waits for a message to the address, ignoring anything else. Returns false when it
+    /// didn't arrive in time.
+    fn wait_for(&self, addr: &str) -> io::Result<bool> {
+        self.socket.set_read_timeout(Some(IDLE))?;
+        let mut buffer = [0; 4096];
+        loop {
+            let len = match self.socket.recv_from(&mut buffer) {
+                Ok((len, _)) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            };
+            if let Ok(packet) = osc::decode(&buffer[..len]) {
+                if packet.into_msgs().iter().any(|m| m.addr == addr) {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+/// Runs all phases, prints the report and writes it as JSON.
+pub(crate) fn run(settings: &BenchmarkSettings) -> io::Result<()> {
+    // The handlers get sockets bound here, so they listen before anything is sent.
+    let controller_socket = UdpSocket::bind("127.0.0.1:0")?;
+    let instrument_socket = UdpSocket::bind("127.0.0.1:0")?;
+    let controller_port = controller_socket.local_addr()?.port();
+    let instrument_port = instrument_socket.local_addr()?.port();
+    let controller = SyntheticPeer::bind(controller_port)?;
+    let instrument = SyntheticPeer::bind(instrument_port)?;
+    // Patchbays, snapshots and bindings the benchmark causes stay out of the user's.
+    let data_dir = tempfile::tempdir()?;
+    let config = synthetic_config(
+        settings.extend,
+        data_dir.path(),
+        (controller_port, controller.socket.local_addr()?.port()),
+        (instrument_port, instrument.socket.local_addr()?.port()),
+    )?;
+    spawn_handler_on(
+        config.clone(),
+        PeerKind::Controller,
+        Receiver::from_socket(controller_socket, 1024),
+    );
+    spawn_handler_on(
+        config,
+        PeerKind::Instrument,
+        Receiver::from_socket(instrument_socket, 1024),
+    );
+
+    let report = Report {
+        extend: settings.extend,
+        phases: vec![
+            param_phase(settings, &controller, &instrument)?,
+            burst_phase(settings, &controller, &instrument)?,
+        ],
+    };
+    print_report(&report);
+
+    let json = serde_json::to_string_pretty(&report)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    std::fs::write(&settings.json, json)?;
+    println!("Wrote {:?}", settings.json);
+    Ok(())
+}
+
+// ********
+// Helpers
+// ********
+
+fn synthetic_config(
+    extend: bool,
+    data_dir: &Path,
+    (controller_local, controller_remote): (u16, u16),
+    (instrument_local, instrument_remote): (u16, u16),
+) -> io::Result<Arc<Config>> {
+    let config = format!(
+        r#"
+        [options]
+        extend = {extend}
+        dryrun = false
+        patch_cache_path = "/patches/"
+        snapshot_path = "/snapshots/"
+        bindings_file = "/bindings.toml"
+        data_dir = {data_dir:?}
+
+        [controller]
+        name = "Synthetic controller"
+        kind = "Controller"
+        local_ip = "127.0.0.1"
+        local_port = "{controller_local}"
+        remote_ip = "127.0.0.1"
+        remote_port = "{controller_remote}"
+        source_port = "0"
+
+        [instrument]
+        name = "Synthetic instrument"
+        kind = "Instrument"
+        local_ip = "127.0.0.1"
+        local_port = "{instrument_local}"
+        remote_ip = "127.0.0.1"
+        remote_port = "{instrument_remote}"
+        source_port = "0"
+        "#
+    );
+    toml::from_str(&config)
+        .map(Arc::new)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// The controller sends parameter changes at the configured rate.
+fn param_phase(
+    settings: &BenchmarkSettings,
+    controller: &SyntheticPeer,
+    instrument: &SyntheticPeer,
+) -> io::Result<PhaseReport> {
+    let receiver = collect(&instrument.socket, settings.messages)?;
+    let mut sent = Vec::with_capacity(settings.messages);
+    let started = Instant::now();
+    for seq in 0..settings.messages {
+        if settings.rate > 0 {
+            let due = started + Duration::from_secs_f64(seq as f64 / settings.rate as f64);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        let value = (seq % 1000) as f32 / 1000.0;
+        sent.push(Instant::now());
+        controller.send(osc::msg(
+            PARAMS[seq % PARAMS.len()],
+            vec![OscType::Float(value), OscType::Int(seq as i32)],
+        ))?;
+    }
+    Ok(phase_report("param", &sent, join(receiver)?, 0))
+}
+
+/// The controller asks for all parameters and the instrument answers each request with a
+/// burst of normalized values.
+fn burst_phase(
+    settings: &BenchmarkSettings,
+    controller: &SyntheticPeer,
+    instrument: &SyntheticPeer,
+) -> io::Result<PhaseReport> {
+    let total = settings.bursts * settings.burst_size;
+    let receiver = collect(&controller.socket, total)?;
+    let mut sent = Vec::with_capacity(total);
+    let mut lost_requests = 0;
+    for _ in 0..settings.bursts {
+        controller.send(osc::msg("/q/all_params", vec![]))?;
+        // The instrument never got to answer a lost request, so its burst is lost too.
+        if !instrument.wait_for("/q/all_params")? {
+            lost_requests += 1;
+            continue;
+        }
+        for i in 0..settings.burst_size {
+            let value = i as f32 / settings.burst_size as f32;
+            let seq = sent.len() as i32;
+            sent.push(Instant::now());
+            instrument.send(osc::msg(
+                format!("/param/a/burst/{}", i),
+                vec![
+                    OscType::String(format!("{:.3} (normalized)", value)),
+                    OscType::Int(seq),
+                ],
+            ))?;
+        }
+    }
+    let mut report = phase_report(
+        "all_params",
+        &sent,
+        join(receiver)?,
+        lost_requests * settings.burst_size,
+    );
+    report.lost_requests = lost_requests;
+    Ok(report)
+}
+
+/// Notes when each sequence number arrives, until all arrived or the peer goes quiet.
+fn collect(socket: &UdpSocket, expected: usize) -> io::Result<JoinHandle<Vec<Option<Instant>>>> {
+    let socket = socket.try_clone()?;
+    socket.set_read_timeout(Some(IDLE))?;
+    Ok(std::thread::spawn(move || {
+        let mut arrived = vec![None; expected];
+        let mut count = 0;
+        let mut buffer = [0; 4096];
+        while count < expected {
+            let Ok((len, _)) = socket.recv_from(&mut buffer) else {
+                break;
+            };
+            let received = Instant::now();
+            let Ok(packet) = osc::decode(&buffer[..len]) else {
+                continue;
+            };
+            for message in packet.into_msgs() {
+                if let Some(OscType::Int(seq)) = message.args.last() {
+                    if let Some(slot @ None) = arrived.get_mut(*seq as usize) {
+                        *slot = Some(received);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        arrived
+    }))
+}
+
+fn join(receiver: JoinHandle<Vec<Option<Instant>>>) -> io::Result<Vec<Option<Instant>>> {
+    receiver
+        .join()
+        .map_err(|_| io::Error::other("Benchmark receiver panicked."))
+}
+
+/// Messages that were `unsent` because their request was lost count as sent and lost.
+fn phase_report(
+    name: &'static str,
+    sent: &[Instant],
+    arrived: Vec<Option<Instant>>,
+    unsent: usize,
+) -> PhaseReport {
+    let mut latencies: Vec<Duration> = sent
+        .iter()
+        .zip(&arrived)
+        .filter_map(|(sent, arrived)| Some(arrived.as_ref()?.saturating_duration_since(*sent)))
+        .collect();
+    latencies.sort();
+    let received = latencies.len();
+    let duration = match (sent.first(), arrived.iter().flatten().max()) {
+        (Some(first), Some(last)) => last.saturating_duration_since(*first),
+        _ => Duration::ZERO,
+    };
+    let expected = sent.len() + unsent;
+    PhaseReport {
+        name,
+        sent: expected,
+        received,
+        lost: expected - received,
+        lost_requests: 0,
+        loss_percent: match expected {
+            0 => 0.0,
+            _ => (expected - received) as f64 * 100.0 / expected as f64,
+        },
+        duration_secs: duration.as_secs_f64(),
+        messages_per_sec: match duration.is_zero() {
+            true => 0.0,
+            false => received as f64 / duration.as_secs_f64(),
+        },
+        latency_ms: distribution(&latencies),
+    }
+}
+
+/// Percentiles of sorted latencies in milliseconds.
+fn distribution(sorted: &[Duration]) -> Option<Distribution> {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let percentile = |p: usize| millis(sorted[(sorted.len() - 1) * p / 100]);
+    let total: Duration = sorted.iter().sum();
+    Some(Distribution {
+        min: millis(*sorted.first()?),
+        mean: millis(total) / sorted.len() as f64,
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: millis(*sorted.last()?),
+    })
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "Benchmark with extensions {}:",
+        if report.extend { "on" } else { "off" }
+    );
+    for phase in &report.phases {
+        println!(
+            "  {}: sent {}, received {}, lost {} ({:.2}%) in {:.3} s, {:.0} messages/s",
+            phase.name,
+            phase.sent,
+            phase.received,
+            phase.lost,
+            phase.loss_percent,
+            phase.duration_secs,
+            phase.messages_per_sec
+        );
+        if phase.lost_requests > 0 {
+            println!("    {} requests lost", phase.lost_requests);
+        }
+        if let Some(latency) = &phase.latency_ms {
+            println!(
+                "    latency ms: min {:.3}, mean {:.3}, p50 {:.3}, p90 {:.3}, p99 {:.3}, max {:.3}",
+                latency.min, latency.mean, latency.p50, latency.p90, latency.p99, latency.max
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Pretends to be Surge XT, so arcflash can be developed and tested without an audio
+//! device. It implements the parts of Surge's OSC spec arcflash relies on: it keeps
+//! parameter values, answers `/q/param/...` and `/q/all_params` with "value (normalized)"
+//! strings, and saves and loads patches as dummy `.fxp` files holding those values.
+
+use clap::{value_parser, Arg, Command};
+use log::{debug, info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+use std::{
+    collections::BTreeMap,
+    io::{self, Error},
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+};
+
+/// Real .fxp files start with these chunk ids. Ours continue with TOML instead of state.
+const FXP_HEADER: &str = "CcnK FPCh arcflash-mock-surge\n";
+
+fn main() {
+    env_logger::init();
+    let matches = Command::new("arcflash-mock-surge")
+        .version("0.1")
+        .about("Simulates the OSC interface of Surge XT for testing arcflash")
+        .arg(
+            Arg::new("listen")
+                .short('l')
+                .long("listen")
+                .value_name("127.0.0.1:53210")
+                .default_value("127.0.0.1:53210")
+                .value_parser(value_parser!(SocketAddr))
+                .help("Where to receive OSC, the instrument's remote address in arcflash."),
+        )
+        .arg(
+            Arg::new("reply")
+                .short('r')
+                .long("reply")
+                .value_name("127.0.0.1:53200")
+                .default_value("127.0.0.1:53200")
+                .value_parser(value_parser!(SocketAddr))
+                .help("Where to send replies, the instrument's local address in arcflash."),
+        )
+        .get_matches();
+    let listen = matches
+        .get_one::<SocketAddr>("listen")
+        .expect("Has a default value.");
+    let reply = matches
+        .get_one::<SocketAddr>("reply")
+        .expect("Has a default value.");
+
+    if let Err(e) = serve(*listen, *reply) {
+        eprintln!("Mock Surge stopped: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn serve(listen: SocketAddr, reply: SocketAddr) -> io::Result<()> {
+    let socket = UdpSocket::bind(listen)?;
+    let mut surge = MockSurge::new();
+    info!(
+        "Mock Surge listening on {} with {} parameters, replying to {}",
+        listen,
+        surge.params.len(),
+        reply
+    );
+
+    let mut buffer = [0; rosc::decoder::MTU];
+    loop {
+        let (len, _) = socket.recv_from(&mut buffer)?;
+        let packet = match rosc::decoder::decode_udp(&buffer[..len]) {
+            Ok((_, packet)) => packet,
+            Err(e) => {
+                warn!("Failed to decode packet: {:?}", e);
+                continue;
+            }
+        };
+        let mut messages = vec![];
+        unfold(packet, &mut messages);
+        for message in messages {
+            for response in surge.handle(message) {
+                let bytes = rosc::encoder::encode(&OscPacket::Message(response))
+                    .map_err(|e| Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+                socket.send_to(&bytes, reply)?;
+            }
+        }
+    }
+}
+
+fn unfold(packet: OscPacket, messages: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(message) => messages.push(message),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                unfold(packet, messages);
+            }
+        }
+    }
+}
+
+/// The state of the simulated instrument, kept by parameter address.
+struct MockSurge {
+    params: BTreeMap<String, f32>,
+}
+
+impl MockSurge {
+    /// Starts with a small patch: the amp, filters, first oscillator and LFO of both
+    /// scenes, and the first effect slot of each.
+    fn new() -> Self {
+        let mut params = BTreeMap::new();
+        for scene in ["a", "b"] {
+            params.insert(format!("/param/{}/amp/gain", scene), 0.75);
+            params.insert(format!("/param/{}/osc/1/pitch", scene), 0.5);
+            params.insert(format!("/param/{}/lfo/1/rate", scene), 0.3);
+            for filter in [1, 2] {
+                params.insert(format!("/param/{}/filter/{}/cutoff", scene, filter), 0.6);
+                params.insert(format!("/param/{}/filter/{}/resonance", scene, filter), 0.2);
+                params.insert(format!("/param/{}/filter/{}/type", scene, filter), 1.0);
+                params.insert(format!("/param/{}/filter/{}/subtype", scene, filter), 0.0);
+            }
+            params.insert(format!("/param/fx/{}/1/type", scene), 0.0);
+        }
+        Self { params }
+    }
+
+    /// Applies a message and returns the replies.
+    fn handle(&mut self, message: OscMessage) -> Vec<OscMessage> {
+        let addr = message.addr.as_str();
+        if addr == "/q/all_params" {
+            debug!("Sending all {} parameters", self.params.len());
+            return self
+                .params
+                .iter()
+                .map(|(addr, value)| value_message(addr, *value))
+                .collect();
+        }
+        if let Some(param) = addr.strip_prefix("/q") {
+            return match self.params.get(param) {
+                Some(value) => vec![value_message(param, *value)],
+                None => {
+                    warn!("Query for unknown parameter {}", param);
+                    vec![]
+                }
+            };
+        }
+        if addr.starts_with("/param/") {
+            match message.args.first().and_then(number) {
+                Some(value) => {
+                    let value = match is_discrete(addr) {
+                        true => value.round().max(0.0),
+                        false => value.clamp(0.0, 1.0),
+                    };
+                    debug!("Setting {} to {}", addr, value);
+                    self.params.insert(message.addr, value);
+                }
+                None => warn!("Ignoring {} without a numeric value", addr),
+            }
+            return vec![];
+        }
+
+        let path = message
+            .args
+            .first()
+            .and_then(|arg| arg.clone().string())
+            .map(fxp_path);
+        let result = match (addr, path) {
+            ("/patch/save", Some(path)) => self.save(&path),
+            ("/patch/load", Some(path)) => self.load(&path),
+            ("/patch/save" | "/patch/load", None) => Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "No patch path given.",
+            )),
+            _ => {
+                debug!("Ignoring {} {:?}", addr, message.args);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            warn!("{} failed: {}", addr, e);
+        }
+        vec![]
+    }
+
+    fn save(&self, path: &PathBuf) -> io::Result<()> {
+        let values = toml::to_string(&self.params)
+            .map_err(|e| Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        std::fs::write(path, format!("{}{}", FXP_HEADER, values))?;
+        info!("Saved patch {:?}", path);
+        Ok(())
+    }
+
+    /// Patches saved by another program don't hold values we can read, so loading one
+    /// keeps the current values.
+    fn load(&mut self, path: &PathBuf) -> io::Result<()> {
+        let contents = std::fs::read(path)?;
+        let Some(values) = String::from_utf8_lossy(&contents)
+            .strip_prefix(FXP_HEADER)
+            .map(String::from)
+        else {
+            info!("Loaded patch {:?} without values we know", path);
+            return Ok(());
+        };
+        let params: BTreeMap<String, f32> = toml::from_str(&values)
+            .map_err(|e| Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.params.extend(params);
+        info!("Loaded patch {:?}", path);
+        Ok(())
+    }
+}
+
+/// Types are indices, everything else is normalized between 0 and 1.
+fn is_discrete(addr: &str) -> bool {
+    addr.ends_with("/type") || addr.ends_with("/subtype")
+}
+
+fn value_message(addr: &str, value: f32) -> OscMessage {
+    let arg = match is_discrete(addr) {
+        true => OscType::Float(value),
+        false => OscType::String(format!("{:.4} (normalized)", value)),
+    };
+    OscMessage {
+        addr: String::from(addr),
+        args: vec![arg],
+    }
+}
+
+fn number(arg: &OscType) -> Option<f32> {
+    match arg {
+        OscType::Float(value) => Some(*value),
+        OscType::Double(value) => Some(*value as f32),
+        OscType::Int(value) => Some(*value as f32),
+        OscType::Long(value) => Some(*value as f32),
+        OscType::String(value) => value.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Surge adds the extension to the path it gets, unless it is there already.
+fn fxp_path(path: String) -> PathBuf {
+    match path.ends_with(".fxp") {
+        true => PathBuf::from(path),
+        false => PathBuf::from(format!("{}.fxp", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage {
+            addr: String::from(addr),
+            args,
+        }
+    }
+
+    #[test]
+    fn answers_queries_with_normalized_strings() {
+        let mut surge = MockSurge::new();
+        surge.handle(message("/param/a/amp/gain", vec![OscType::Float(0.25)]));
+        assert_eq!(
+            surge.handle(message("/q/param/a/amp/gain", vec![])),
+            vec![message(
+                "/param/a/amp/gain",
+                vec![OscType::String(String::from("0.2500 (normalized)"))]
+            )]
+        );
+        assert!(surge
+            .handle(message("/q/param/a/nothing", vec![]))
+            .is_empty());
+
+        let all = surge.handle(message("/q/all_params", vec![]));
+        assert_eq!(all.len(), surge.params.len());
+        assert!(all.contains(&message(
+            "/param/a/filter/1/type",
+            vec![OscType::Float(1.0)]
+        )));
+    }
+
+    #[test]
+    fn saves_and_loads_patches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Bright Pad");
+        let path = path.to_string_lossy();
+        let mut surge = MockSurge::new();
+
+        surge.handle(message("/param/b/lfo/1/rate", vec![OscType::Float(0.9)]));
+        surge.handle(message(
+            "/patch/save",
+            vec![OscType::String(path.to_string())],
+        ));
+        assert!(dir.path().join("Bright Pad.fxp").exists());
+
+        surge.handle(message("/param/b/lfo/1/rate", vec![OscType::Float(0.1)]));
+        surge.handle(message(
+            "/patch/load",
+            vec![OscType::String(path.to_string())],
+        ));
+        assert_eq!(surge.params["/param/b/lfo/1/rate"], 0.9);
+    }
+}
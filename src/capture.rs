@@ -0,0 +1,135 @@
+//! Recording received packets to a capture file and reading them back for replay.
+//!
+//! A capture file starts with a magic number and the unix time in milliseconds at which the
+//! recording started. Every packet after that is stored as the offset from the start in
+//! microseconds, the kind of peer it came from and the raw bytes as received.
+
+use crate::peer::PeerKind;
+use log::{info, warn};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Error, Read, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: &[u8; 8] = b"ARCFCAP1";
+
+/// A single received packet as stored in a capture file.
+#[derive(Debug, Clone)]
+pub(crate) struct CapturedPacket {
+    pub offset: Duration,
+    pub peer_kind: PeerKind,
+    pub bytes: Vec<u8>,
+}
+
+struct Recorder {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+fn recorder() -> &'static Mutex<Option<Recorder>> {
+    static RECORDER: OnceLock<Mutex<Option<Recorder>>> = OnceLock::new();
+    RECORDER.get_or_init(|| Mutex::new(None))
+}
+
+/// Starts recording every received packet to the given file.
+pub(crate) fn start_recording(path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let unix_millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&unix_millis.to_be_bytes())?;
+    writer.flush()?;
+
+    *recorder()
+        .lock()
+        .map_err(|_| Error::other("Capture lock was poisoned."))? = Some(Recorder {
+        writer,
+        started: Instant::now(),
+    });
+    info!("Recording received packets to {:?}", path);
+    Ok(())
+}
+
+/// Adds a received packet to the capture file, if we are recording.
+pub(crate) fn record(peer_kind: &PeerKind, bytes: &[u8]) {
+    let Ok(mut recorder) = recorder().lock() else {
+        return;
+    };
+    let Some(active) = recorder.as_mut() else {
+        return;
+    };
+    let offset = active.started.elapsed().as_micros() as u64;
+    if let Err(e) = write_packet(&mut active.writer, offset, peer_kind, bytes) {
+        warn!("Failed to record packet, recording stopped: {}", e);
+        *recorder = None;
+    }
+}
+
+/// Reads all packets from a capture file.
+pub(crate) fn read_capture(path: &Path) -> io::Result<Vec<CapturedPacket>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is not an arcflash capture file.", path),
+        ));
+    }
+    let mut started = [0u8; 8];
+    reader.read_exact(&mut started)?;
+
+    let mut packets = vec![];
+    loop {
+        let mut offset = [0u8; 8];
+        match reader.read_exact(&mut offset) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        let peer_kind = match header[0] {
+            0 => PeerKind::Controller,
+            1 => PeerKind::Instrument,
+            other => {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown peer kind {} in capture file.", other),
+                ))
+            }
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes)?;
+        packets.push(CapturedPacket {
+            offset: Duration::from_micros(u64::from_be_bytes(offset)),
+            peer_kind,
+            bytes,
+        });
+    }
+    Ok(packets)
+}
+
+fn write_packet(
+    writer: &mut BufWriter<File>,
+    offset: u64,
+    peer_kind: &PeerKind,
+    bytes: &[u8],
+) -> io::Result<()> {
+    let kind: u8 = match peer_kind {
+        PeerKind::Controller => 0,
+        PeerKind::Instrument => 1,
+    };
+    writer.write_all(&offset.to_be_bytes())?;
+    writer.write_all(&[kind])?;
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)?;
+    // Flush every packet so a capture is usable even if arcflash doesn't shut down cleanly.
+    writer.flush()
+}
@@ -1,14 +1,336 @@
-use crate::peer::Peer;
-use serde::Deserialize;
+use crate::{filter::FilterRule, osc::AddressPattern, peer::Peer};
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{self, Read};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Deserialize, Debug)]
 pub struct Options {
     pub extend: bool,
     pub dryrun: bool,
     pub patch_cache_path: String,
+    #[serde(default = "default_snapshot_path")]
+    pub snapshot_path: String,
+    #[serde(default)]
+    pub setlist_file: Option<PathBuf>,
+    #[serde(default = "default_bindings_file")]
+    pub bindings_file: String,
+    /// Archives and captures named over OSC are read and written in this directory.
+    #[serde(default = "default_exchange_path")]
+    pub exchange_path: String,
+    /// The paths above are resolved in this directory, the local config dir of this machine
+    /// when left out.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+}
+
+fn default_snapshot_path() -> String {
+    String::from("/Arcflash Snapshots/")
+}
+
+fn default_bindings_file() -> String {
+    String::from("/Arcflash/bindings.toml")
+}
+
+fn default_exchange_path() -> String {
+    String::from("/Arcflash/exchange/")
+}
+
+/// Directories with patches the controller can browse.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LibraryConfig {
+    pub paths: Vec<PathBuf>,
+    pub page_size: usize,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self {
+            paths: vec![],
+            page_size: 10,
+        }
+    }
+}
+
+/// Directories with .scl and .kbm tuning files.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TuningConfig {
+    pub paths: Vec<PathBuf>,
+}
+
+/// A named set of parameters `/sys/random <name>` changes at once.
+#[derive(Deserialize, Debug)]
+pub struct RandomGroup {
+    pub name: String,
+    pub params: Vec<RandomParam>,
+}
+
+/// Parameters matching the OSC address pattern get a random value between `min` and `max`.
+/// The amount sets how far the parameter moves from its current value towards that random
+/// value.
+#[derive(Deserialize, Debug)]
+pub struct RandomParam {
+    pub pattern: AddressPattern,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_one")]
+    pub max: f32,
+    #[serde(default = "default_one")]
+    pub amount: f32,
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+/// One controller address driving several instrument parameters.
+#[derive(Deserialize, Debug)]
+pub struct Macro {
+    pub name: String,
+    pub address: String,
+    pub targets: Vec<MacroTarget>,
+}
+
+/// The macro position (0 to 1) is shaped by the curve, optionally inverted and then
+/// scaled to the range between `min` and `max`.
+#[derive(Deserialize, Debug)]
+pub struct MacroTarget {
+    pub param: String,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_one")]
+    pub max: f32,
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+/// Where traffic captures go and how large they get before rotating.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct PcapConfig {
+    pub file: PathBuf,
+    pub max_size_mb: u64,
+    pub max_files: usize,
+}
+
+impl Default for PcapConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("arcflash.pcap"),
+            max_size_mb: 10,
+            max_files: 5,
+        }
+    }
+}
+
+/// The message journal is off unless a `file` is set here or given with `--journal`.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct JournalConfig {
+    pub file: Option<PathBuf>,
+    pub max_size_mb: u64,
+    pub max_files: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_size_mb: 10,
+            max_files: 5,
+        }
+    }
+}
+
+/// Serves Prometheus metrics over HTTP on the `listen` address, e.g. "127.0.0.1:9464".
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub listen: Option<String>,
+}
+
+/// Round trip probes keep the last `window` samples per peer. With `monitor` on, both
+/// peers are probed every `interval` seconds and the controller is alerted when a round
+/// trip takes longer than `threshold_ms`. The instrument is probed by querying
+/// `probe_param`, unless it is another arcflash that answers pings.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LatencyConfig {
+    pub window: usize,
+    pub monitor: bool,
+    pub interval: f32,
+    pub threshold_ms: f32,
+    pub instrument_probe: ProbeKind,
+    pub probe_param: String,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self {
+            window: 32,
+            monitor: false,
+            interval: 5.0,
+            threshold_ms: 20.0,
+            instrument_probe: ProbeKind::default(),
+            probe_param: String::from("/param/a/volume"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+    /// Query a parameter and wait for its value.
+    #[default]
+    Query,
+    /// Send /sys/ping and wait for /sys/pong.
+    Ping,
+}
+
+/// Loops are quantized to whole bars when a tempo is set.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LooperConfig {
+    pub tempo: Option<f32>,
+    pub beats_per_bar: u32,
+}
+
+impl Default for LooperConfig {
+    fn default() -> Self {
+        Self {
+            tempo: None,
+            beats_per_bar: 4,
+        }
+    }
+}
+
+/// A generator that moves an instrument parameter around its center value. `rate` is in
+/// cycles per second, `depth` is how far the parameter moves from the center. Without a
+/// center the value the parameter has when the modulator is switched on is used.
+#[derive(Deserialize, Debug)]
+pub struct Modulator {
+    pub name: String,
+    pub param: String,
+    #[serde(default)]
+    pub shape: Shape,
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+    #[serde(default = "default_depth")]
+    pub depth: f32,
+    #[serde(default)]
+    pub center: Option<f32>,
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+fn default_rate() -> f32 {
+    0.1
+}
+
+fn default_depth() -> f32 {
+    0.1
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    #[default]
+    Sine,
+    Triangle,
+    RandomWalk,
+    SampleAndHold,
+}
+
+/// The step sequencer starts with this many steps (16 or 32) at this tempo.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SequencerConfig {
+    pub steps: usize,
+    pub tempo: f32,
+    pub steps_per_beat: u32,
+}
+
+impl Default for SequencerConfig {
+    fn default() -> Self {
+        Self {
+            steps: 16,
+            tempo: 120.0,
+            steps_per_beat: 4,
+        }
+    }
+}
+
+/// Notes the controller plays on `address` are quantized to the scale in the key, shifted
+/// by whole octaves and can be turned into chords. XY pads play notes between `xy_low` and
+/// `xy_high`. Held notes are released when the controller is silent for
+/// `release_after_silence` seconds, 0 never releases them.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct NotesConfig {
+    pub address: String,
+    pub scale: Scale,
+    pub key: String,
+    pub octave: i32,
+    pub velocity_curve: Curve,
+    pub chord: Chord,
+    pub xy_low: u8,
+    pub xy_high: u8,
+    pub release_after_silence: f32,
+}
+
+impl Default for NotesConfig {
+    fn default() -> Self {
+        Self {
+            address: String::from("/notes"),
+            scale: Scale::default(),
+            key: String::from("C"),
+            octave: 0,
+            velocity_curve: Curve::default(),
+            chord: Chord::default(),
+            xy_low: 48,
+            xy_high: 72,
+            release_after_silence: 30.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scale {
+    #[default]
+    Chromatic,
+    Major,
+    Minor,
+    Dorian,
+    Mixolydian,
+    HarmonicMinor,
+    Pentatonic,
+    MinorPentatonic,
+    Blues,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Chord {
+    #[default]
+    Off,
+    Power,
+    Octave,
+    Triad,
+    Seventh,
 }
 
 #[derive(Deserialize, Debug)]
@@ -16,6 +338,33 @@ pub struct Config {
     pub options: Options,
     pub controller: Peer,
     pub instrument: Peer,
+    #[serde(default)]
+    pub library: LibraryConfig,
+    #[serde(default)]
+    pub tuning: TuningConfig,
+    #[serde(default, rename = "random_group")]
+    pub random_groups: Vec<RandomGroup>,
+    #[serde(default, rename = "macro")]
+    pub macros: Vec<Macro>,
+    #[serde(default, rename = "filter")]
+    pub filters: Vec<FilterRule>,
+    #[serde(default)]
+    pub pcap: PcapConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub latency: LatencyConfig,
+    #[serde(default)]
+    pub looper: LooperConfig,
+    #[serde(default, rename = "modulator")]
+    pub modulators: Vec<Modulator>,
+    #[serde(default)]
+    pub sequencer: SequencerConfig,
+    /// Note input is only played with a `[notes]` section.
+    #[serde(default)]
+    pub notes: Option<NotesConfig>,
 }
 
 pub(crate) fn read_config_from_file(path: &PathBuf) -> io::Result<Config> {
@@ -27,3 +376,37 @@ pub(crate) fn read_config_from_file(path: &PathBuf) -> io::Result<Config> {
         Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
     }
 }
+
+/// Resolves a directory from the config relative to the data dir from the options, or the
+/// local config dir of this machine.
+pub(crate) fn data_dir(options: &Options, subdir: &str) -> io::Result<PathBuf> {
+    let mut path = match &options.data_dir {
+        Some(dir) => dir.clone(),
+        None => dirs::config_local_dir().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Could not find home directory")
+        })?,
+    };
+    path.push(subdir.strip_prefix('/').unwrap_or(subdir));
+    Ok(path)
+}
+
+/// Resolves a file name a peer sent inside the exchange directory. Names that could point
+/// anywhere else, absolute or with `..`, are refused.
+pub(crate) fn exchange_file(options: &Options, name: &str) -> io::Result<PathBuf> {
+    let name = Path::new(name);
+    let plain = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !plain || name.as_os_str().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Refusing {:?}, only names inside the exchange directory are allowed.",
+                name
+            ),
+        ));
+    }
+    let dir = data_dir(options, &options.exchange_path)?;
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join(name))
+}
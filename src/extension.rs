@@ -1,84 +1,167 @@
 use crate::config::Config;
-use crate::{labeler::LabeledMessage, peer::PeerKind};
+use crate::{filter, labeler::LabeledMessage, osc, peer::PeerKind, stats};
 use log::debug;
-use regex::Regex;
 use rosc::OscType;
 use std::io;
 use std::sync::Arc;
-use std::{collections::HashMap, sync::OnceLock};
 
 use self::name_lookup::lookup;
 use self::names::{filtertypes, fx_types};
 
+mod macros;
 mod name_lookup;
 mod names;
+pub(crate) mod param_store;
 
-mod system;
-
-fn address_patterns() -> &'static HashMap<&'static str, Regex> {
-    static HASHMAP: OnceLock<HashMap<&'static str, Regex>> = OnceLock::new();
-    HASHMAP.get_or_init(|| {
-        let mut m = HashMap::new();
-        m.insert(
-            "filter_type",
-            Regex::new(r"^/param/./filter/./type").expect("Unable to compile regex."),
-        );
-        m.insert(
-            "filter_subtype",
-            Regex::new(r"^/param/./filter/./subtype").expect("Unable to compile regex."),
-        );
-        m.insert(
-            "fx_type",
-            Regex::new(r"^/param/fx/.*/./type").expect("Unable to compile regex."),
-        );
-
-        m
-    })
-}
+pub(crate) mod system;
+
+/// Parameters whose values are types with names.
+const FILTER_TYPE: &str = "/param/?/filter/?/type";
+const FX_TYPE: &str = "/param/fx/*/*/type";
 
 /// Inspect messages and route them accordingly. Returns messages after potential alterations.
+/// A single message may result in several messages, possibly to different peers.
 pub(crate) fn extension_processor(
     config: Arc<Config>,
-    mut labeled: LabeledMessage,
-) -> Result<LabeledMessage, io::Error> {
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    if labeled.peer_recv.kind == PeerKind::Controller {
+        system::notes::controller_seen(&config);
+    }
+
     // Handle system messages
-    if labeled.message.addr.contains("/sys/") {
-        return system::system_handler(config, labeled);
+    if labeled.message.addr.starts_with("/sys/") {
+        stats::extension_hit("system");
+        return stats::time_extension("system", || system::system_handler(config, labeled));
+    }
+
+    // A pattern from the controller stands for every known parameter it matches. Known
+    // parameters are never patterns, so each expanded message goes through once. The
+    // filter rules only saw the pattern, so they check each address it stands for.
+    if labeled.peer_recv.kind == PeerKind::Controller && osc::is_pattern(&labeled.message.addr) {
+        let expanded = param_store::expand(&labeled.message.addr);
+        if !expanded.is_empty() {
+            stats::extension_hit("expand");
+            let mut messages = vec![];
+            for addr in expanded {
+                if !filter::allows(&config.filters, &labeled.peer_recv, &addr) {
+                    debug!("Filtered expanded address {}", addr);
+                    stats::filtered(&labeled.peer_recv.kind);
+                    continue;
+                }
+                let mut single = labeled.clone();
+                single.message.addr = addr;
+                messages.extend(parameter_processor(config.clone(), single)?);
+            }
+            return Ok(messages);
+        }
     }
 
-    // Handle strings with both real and normalized values
+    parameter_processor(config, labeled)
+}
+
+/// Everything after system messages and pattern expansion, for a single address.
+fn parameter_processor(
+    config: Arc<Config>,
+    mut labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    // Replies to latency probes of the instrument complete the probe and still go on.
+    let probed = system::latency::probe_reply(&config, &labeled);
+    if probed.is_some() {
+        stats::extension_hit("latency");
+    }
+
+    // Learned bindings rewrite controller addresses to instrument parameters.
+    if labeled.peer_recv.kind == PeerKind::Controller {
+        let addr = labeled.message.addr.clone();
+        stats::time_extension("learn", || {
+            system::learn::to_instrument(&config, &mut labeled)
+        });
+        if labeled.message.addr != addr {
+            stats::extension_hit("learn");
+        }
+    }
+
+    // Macros fan out a single controller message to several instrument parameters.
+    if labeled.peer_recv.kind == PeerKind::Controller {
+        if let Some(messages) = stats::time_extension("macro", || macros::expand(&config, &labeled))
+        {
+            stats::extension_hit("macro");
+            return Ok(messages);
+        }
+    }
+
+    // Notes played on the controller are quantized and sent to the instrument as /mnote.
+    if labeled.peer_recv.kind == PeerKind::Controller {
+        if let Some(messages) =
+            stats::time_extension("notes", || system::notes::play(&config, &labeled))
+        {
+            stats::extension_hit("notes");
+            return Ok(messages);
+        }
+    }
+
+    // Keep track of parameter values in the form the instrument understands.
     if labeled.peer_send.kind == PeerKind::Controller {
-        if let Some(OscType::String(valstring)) = labeled.message.args.first() {
-            if valstring.contains("(normalized)") {
-                debug!("Normalized value detected in string.");
-                if let Some(float_val) = valstring
-                    .split_whitespace()
-                    .nth_back(1)
-                    .and_then(|s| s.parse::<f32>().ok())
-                {
-                    if let Some(arg) = labeled.message.args.get_mut(0) {
-                        *arg = OscType::Float(float_val);
-                    }
+        stats::time_extension("normalize", || normalize_value_string(&mut labeled));
+        param_store::record(&labeled.message);
+    }
+    let mut labeled = stats::time_extension("type_lookup", || type_lookup(labeled))?;
+    if labeled.peer_send.kind == PeerKind::Instrument {
+        system::undo::record(&labeled.message);
+        system::looper::record(&labeled.message);
+        param_store::record(&labeled.message);
+    }
+
+    // Parameters bound to a controller address go back to that address.
+    let mut learned = None;
+    if labeled.peer_send.kind == PeerKind::Controller {
+        let addr = labeled.message.addr.clone();
+        learned = stats::time_extension("learn", || {
+            system::learn::to_controller(&config, &mut labeled)
+        });
+        if labeled.message.addr != addr || learned.is_some() {
+            stats::extension_hit("learn");
+        }
+    }
+
+    Ok([labeled]
+        .into_iter()
+        .chain(learned)
+        .chain(probed.into_iter().flatten())
+        .collect())
+}
+
+/// Handle strings with both real and normalized values
+fn normalize_value_string(labeled: &mut LabeledMessage) {
+    if let Some(OscType::String(valstring)) = labeled.message.args.first() {
+        if valstring.contains("(normalized)") {
+            debug!("Normalized value detected in string.");
+            stats::extension_hit("normalize");
+            if let Some(float_val) = valstring
+                .split_whitespace()
+                .nth_back(1)
+                .and_then(|s| s.parse::<f32>().ok())
+            {
+                if let Some(arg) = labeled.message.args.get_mut(0) {
+                    *arg = OscType::Float(float_val);
                 }
             }
         }
     }
+}
 
+/// Translate between type numbers and names for filters and effects.
+fn type_lookup(labeled: LabeledMessage) -> Result<LabeledMessage, io::Error> {
     // Handle filter types
-    if address_patterns()
-        .get("filter_type")
-        .unwrap()
-        .is_match(labeled.message.addr.as_str())
-    {
+    if osc::matches(FILTER_TYPE, &labeled.message.addr) {
+        stats::extension_hit("filter_type");
         return lookup(labeled, filtertypes());
     }
 
     // Handle fx types
-    if address_patterns()
-        .get("fx_type")
-        .unwrap()
-        .is_match(labeled.message.addr.as_str())
-    {
+    if osc::matches(FX_TYPE, &labeled.message.addr) {
+        stats::extension_hit("fx_type");
         return lookup(labeled, fx_types());
     }
 
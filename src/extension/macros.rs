@@ -0,0 +1,52 @@
+use crate::config::{Config, Curve, Macro, MacroTarget};
+use crate::{extension::param_store, labeler::LabeledMessage, osc};
+use log::debug;
+use rosc::OscType;
+
+/// Turns a controller message on a macro address into messages for every target parameter
+/// and reports the macro position back to the controller. Returns `None` for messages that
+/// are not addressed to a macro.
+pub(super) fn expand(config: &Config, labeled: &LabeledMessage) -> Option<Vec<LabeledMessage>> {
+    let macro_ = config
+        .macros
+        .iter()
+        .find(|m| m.address == labeled.message.addr)?;
+    let position = osc::as_f32(labeled.message.args.first()?)?.clamp(0.0, 1.0);
+    debug!("Macro {} moved to {}", macro_.name, position);
+
+    let mut messages: Vec<LabeledMessage> = macro_
+        .targets
+        .iter()
+        .map(|target| {
+            let message = osc::msg(
+                target.param.as_str(),
+                vec![OscType::Float(target_value(target, position))],
+            );
+            param_store::record(&message);
+            LabeledMessage::new(labeled.peer_recv.clone(), labeled.instrument(), message)
+        })
+        .collect();
+    messages.push(position_message(labeled, macro_, position));
+    Some(messages)
+}
+
+fn target_value(target: &MacroTarget, position: f32) -> f32 {
+    let shaped = match target.curve {
+        Curve::Linear => position,
+        Curve::Exponential => position * position,
+        Curve::Logarithmic => position.sqrt(),
+    };
+    let shaped = if target.invert { 1.0 - shaped } else { shaped };
+    target.min + (target.max - target.min) * shaped
+}
+
+fn position_message(labeled: &LabeledMessage, macro_: &Macro, position: f32) -> LabeledMessage {
+    LabeledMessage::new(
+        labeled.controller(),
+        labeled.controller(),
+        osc::msg(
+            format!("/sys/macro/{}", macro_.name),
+            vec![OscType::Float(position)],
+        ),
+    )
+}
@@ -66,8 +66,5 @@ pub(super) fn lookup(
 }
 
 fn reverse_lookup(value: &String, map: &HashMap<i32, String>) -> Option<i32> {
-    match map.iter().find(|f| f.1 == value) {
-        Some(kv_pair) => Some(*kv_pair.0),
-        None => None,
-    }
+    map.iter().find(|f| f.1 == value).map(|kv_pair| *kv_pair.0)
 }
@@ -0,0 +1,65 @@
+use crate::osc;
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+};
+
+/// The last known value of every instrument parameter that passed through Arcflash.
+/// Values are kept the way the instrument understands them, so they can be sent back as-is.
+fn store() -> &'static Mutex<BTreeMap<String, osc::Type>> {
+    static STORE: OnceLock<Mutex<BTreeMap<String, osc::Type>>> = OnceLock::new();
+    STORE.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Remember the value of a parameter message. Anything that is not a numeric /param/ message
+/// is ignored, and so are address patterns, which stand for parameters rather than being one.
+pub(crate) fn record(message: &osc::Message) {
+    if !message.addr.starts_with("/param/") || osc::is_pattern(&message.addr) {
+        return;
+    }
+    let Some(value) = message.args.first() else {
+        return;
+    };
+    match value {
+        osc::Type::Float(_)
+        | osc::Type::Double(_)
+        | osc::Type::Int(_)
+        | osc::Type::Long(_)
+        | osc::Type::Bool(_) => {
+            if let Ok(mut store) = store().lock() {
+                store.insert(message.addr.clone(), value.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The last known value of a single parameter.
+pub(crate) fn get(addr: &str) -> Option<osc::Type> {
+    store().lock().ok()?.get(addr).cloned()
+}
+
+/// A copy of all known parameter values.
+pub(crate) fn all() -> BTreeMap<String, osc::Type> {
+    store()
+        .lock()
+        .map(|store| store.clone())
+        .unwrap_or_default()
+}
+
+/// The known parameters matching an address pattern. A pattern like `/q/param/a/*/cutoff`
+/// asks for the values of the parameters it matches, so the query stays in front.
+pub(crate) fn expand(pattern: &str) -> Vec<String> {
+    let (query, pattern) = match pattern.starts_with("/q/") {
+        true => ("/q", &pattern[2..]),
+        false => ("", pattern),
+    };
+    let Ok(store) = store().lock() else {
+        return vec![];
+    };
+    store
+        .keys()
+        .filter(|addr| !osc::is_pattern(addr) && osc::matches(pattern, addr))
+        .map(|addr| format!("{}{}", query, addr))
+        .collect()
+}
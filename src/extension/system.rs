@@ -1,8 +1,25 @@
-use crate::{config::Config, labeler::LabeledMessage, osc};
+use crate::{
+    config::{self, Config},
+    labeler::LabeledMessage,
+    osc, pcap, stats,
+};
 use log::{debug, warn};
 use rosc::OscType;
 use std::{io, sync::Arc};
-mod patchbay;
+
+pub(crate) mod latency;
+pub(crate) mod learn;
+mod library;
+pub(crate) mod looper;
+pub(crate) mod modulator;
+pub(crate) mod notes;
+pub(crate) mod patchbay;
+mod random;
+mod sequencer;
+mod setlist;
+mod snapshot;
+mod tuning;
+pub(crate) mod undo;
 
 /// System messages are addressed to Arcflash i.e. the packet router.
 /// If the packet router runs on the system the instrument runs on, then system
@@ -11,9 +28,12 @@ mod patchbay;
 pub fn system_handler(
     config: Arc<Config>,
     labeled: LabeledMessage,
-) -> Result<LabeledMessage, io::Error> {
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let addr = labeled.message.addr.clone();
+    let is = |pattern: &str| osc::matches(pattern, &addr);
+
     // System average load
-    if labeled.message.addr.contains("/sys/q/system_load") {
+    if is("/sys/q/system_load") {
         let addr = String::from("/sys/system_load");
 
         if let Ok(load) = sys_info::loadavg() {
@@ -22,46 +42,215 @@ pub fn system_handler(
                 load.one, load.five, load.fifteen
             ));
             let return_message = build_return_message(labeled, addr, load_message);
-            return Ok(return_message);
+            return Ok(vec![return_message]);
         }
     }
 
     // Cpu speed
-    if labeled.message.addr.contains("/sys/q/cpu_speed") {
+    if is("/sys/q/cpu_speed") {
         let addr = String::from("/sys/cpu_speed");
 
         match sys_info::cpu_speed() {
             Ok(cpu_speed) => {
-                let load_message = OscType::String(String::from(format!("{} mhz", cpu_speed)));
+                let load_message = OscType::String(format!("{} mhz", cpu_speed));
                 let return_message = build_return_message(labeled, addr, load_message);
-                return Ok(return_message);
+                return Ok(vec![return_message]);
             }
             Err(e) => {
                 warn!("Unable to get cpu speed.");
-                let error_msg = OscType::String(String::from(format!("Error: {}", e)));
-                return Ok(build_return_message(labeled, addr, error_msg));
+                let error_msg = OscType::String(format!("Error: {}", e));
+                return Ok(vec![build_return_message(labeled, addr, error_msg)]);
             }
         }
     }
 
+    // Traffic counters, one message per counter
+    if is("/sys/q/stats") {
+        return Ok(stats::osc_report()
+            .into_iter()
+            .map(|(addr, value)| build_return_message(labeled.clone(), addr, value))
+            .collect());
+    }
+
+    // Round trips to the peers
+    if is("/sys/q/latency") {
+        return latency::query(config, labeled);
+    }
+    if is("/sys/latency/monitor") {
+        return latency::monitor(config, labeled);
+    }
+    if is("/sys/ping") {
+        return latency::ping(labeled);
+    }
+    if is("/sys/pong") {
+        return latency::pong(config, labeled);
+    }
+
     // Is arcflash enabled?
-    if labeled.message.addr.contains("/sys/q/arcflash") {
+    if is("/sys/q/arcflash") {
         let addr = String::from("/sys/arcflash");
 
         let load_message = OscType::Bool(true);
         let return_message = build_return_message(labeled, addr, load_message);
-        return Ok(return_message);
+        return Ok(vec![return_message]);
+    }
+
+    // Capture traffic to a pcap file
+    if is("/sys/capture/start") {
+        let addr = String::from("/sys/capture");
+        let file = labeled
+            .message
+            .args
+            .first()
+            .and_then(|arg| arg.clone().string())
+            .map(|name| config::exchange_file(&config.options, &name))
+            .transpose();
+        let started = file.and_then(|file| pcap::start(file.as_deref(), &config.pcap));
+        let status = match started {
+            Ok(path) => format!("Capturing to {}", path.to_string_lossy()),
+            Err(e) => format!("Error: {}", e),
+        };
+        return Ok(vec![build_return_message(
+            labeled,
+            addr,
+            OscType::String(status),
+        )]);
+    }
+    if is("/sys/capture/stop") {
+        let addr = String::from("/sys/capture");
+        let status = match pcap::stop() {
+            Ok(true) => String::from("Capture stopped"),
+            Ok(false) => String::from("Not capturing"),
+            Err(e) => format!("Error: {}", e),
+        };
+        return Ok(vec![build_return_message(
+            labeled,
+            addr,
+            OscType::String(status),
+        )]);
     }
 
     // Handle loading and saving to patch bays
-    if labeled.message.addr.contains("/sys/patchbay/save") {
-        return patchbay::save_patch(config, labeled);
+    if is("/sys/patchbay/save") {
+        return patchbay::save_patch(config, labeled).map(|m| vec![m]);
     };
-    if labeled.message.addr.contains("/sys/patchbay/load") {
+    if is("/sys/patchbay/load") {
         return patchbay::load_patch(config, labeled);
     };
-    if labeled.message.addr.contains("/sys/patchbay/check") {
-        return patchbay::check_patchbay(config, labeled);
+    if is("/sys/patchbay/check") {
+        return patchbay::check_patchbay(config, labeled).map(|m| vec![m]);
+    };
+    if is("/sys/patchbay/export") {
+        return patchbay::export_patchbays(config, labeled).map(|m| vec![m]);
+    };
+    if is("/sys/patchbay/import") {
+        return patchbay::import_patchbays(config, labeled).map(|m| vec![m]);
+    };
+
+    // Handle saving and recalling parameter snapshots
+    if is("/sys/snapshot/save") {
+        return snapshot::save_snapshot(config, labeled).map(|m| vec![m]);
+    };
+    if is("/sys/snapshot/load") {
+        return snapshot::load_snapshot(config, labeled);
+    };
+    if is("/sys/snapshot/check") {
+        return snapshot::check_snapshot(config, labeled).map(|m| vec![m]);
+    };
+    if is("/sys/snapshot/query") {
+        return snapshot::query_params(labeled).map(|m| vec![m]);
+    };
+
+    // Step through the setlist
+    if is("/sys/setlist/next") {
+        return setlist::next_song(config, labeled);
+    };
+    if is("/sys/setlist/prev") {
+        return setlist::previous_song(config, labeled);
+    };
+    if is("/sys/setlist/goto") {
+        return setlist::goto_requested_song(config, labeled);
+    };
+
+    // Browse the instrument's patch library
+    if is("/sys/library/categories") {
+        return library::list_categories(config, labeled);
+    };
+    if is("/sys/library/page") {
+        return library::list_page(config, labeled);
+    };
+    if is("/sys/library/load") {
+        return library::load_patch(config, labeled);
+    };
+    if is("/sys/library/rescan") {
+        return library::rescan(config, labeled);
+    };
+
+    // Browse and load microtunings
+    if is("/sys/tuning/list") {
+        return tuning::list(config, labeled);
+    };
+    if is("/sys/tuning/next") {
+        return tuning::next(config, labeled);
+    };
+    if is("/sys/tuning/load") {
+        return tuning::load(config, labeled);
+    };
+    if is("/sys/tuning/reset") {
+        return tuning::reset(labeled);
+    };
+
+    // Undo and redo parameter changes made from the controller
+    if is("/sys/undo") {
+        return undo::undo(labeled);
+    };
+    if is("/sys/redo") {
+        return undo::redo(labeled);
+    };
+
+    // Randomize groups of parameters
+    if is("/sys/random/revert") {
+        return random::revert(labeled);
+    };
+    if is("/sys/random") {
+        return random::randomize(config, labeled);
+    };
+
+    // Learn bindings between controller addresses and instrument parameters
+    if is("/sys/learn/start") {
+        return learn::start(labeled);
+    };
+    if is("/sys/learn/stop") {
+        return learn::stop(labeled);
+    };
+    if is("/sys/learn/list") {
+        return learn::list(config, labeled);
+    };
+    if is("/sys/learn/clear") {
+        return learn::clear(config, labeled);
+    };
+
+    // Record and play loops of controller gestures
+    if is("/sys/loop/*/*") {
+        return looper::loop_handler(config, labeled);
+    };
+
+    // Control the modulators
+    if is("/sys/mod/*/*") {
+        return modulator::mod_handler(labeled);
+    };
+
+    // Note input settings and releasing all notes
+    if is("/sys/notes/*") {
+        return notes::settings_handler(config, labeled);
+    };
+    if is("/sys/panic") {
+        return notes::panic(config, labeled);
+    };
+
+    // Edit and run the step sequencer
+    if is("/sys/seq/*") || is("/sys/seq/step/*/*") {
+        return sequencer::seq_handler(config, labeled);
     };
 
     // If we can't match any addresses, return a not found message.
@@ -75,7 +264,7 @@ pub fn system_handler(
         peer_recv: labeled.peer_recv.clone(),
         peer_send: labeled.peer_recv.clone(),
     };
-    Ok(return_message)
+    Ok(vec![return_message])
 }
 
 fn build_return_message(labeled: LabeledMessage, addr: String, content: OscType) -> LabeledMessage {
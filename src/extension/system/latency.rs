@@ -0,0 +1,370 @@
+//! Round trip probes to the controller and the instrument, to tell a slow network apart
+//! from a slow instrument. The controller, or another arcflash, answers `/sys/ping <id>`
+//! with `/sys/pong <id>`. Surge doesn't know that convention, so it is probed by
+//! querying a parameter and waiting for its value.
+
+use crate::{
+    config::{Config, ProbeKind},
+    labeler::LabeledMessage,
+    osc,
+    peer::{Peer, PeerKind},
+    sender::send_message,
+};
+use log::{debug, warn};
+use rosc::OscType;
+use std::{
+    collections::VecDeque,
+    io::{self, Error},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant},
+};
+
+use super::build_return_message;
+
+/// A probe without a reply after this long is counted as lost.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the background thread looks for lost probes and sends new ones.
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+struct Probe {
+    id: i32,
+    sent: Instant,
+    /// The controller asked for this probe and wants to hear the result.
+    report: bool,
+}
+
+#[derive(Debug, Default)]
+struct PeerLatency {
+    samples: VecDeque<Duration>,
+    lost: u64,
+    pending: Option<Probe>,
+}
+
+impl PeerLatency {
+    /// Takes the pending probe if it is the one that was answered or timed out.
+    fn take_pending(&mut self, matches: impl FnOnce(&Probe) -> bool) -> Option<Probe> {
+        if self.pending.as_ref().is_some_and(matches) {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Latency {
+    next_id: i32,
+    /// Switched on or off from the controller, otherwise as configured.
+    monitor: Option<bool>,
+    last_probe: Option<Instant>,
+    controller: PeerLatency,
+    instrument: PeerLatency,
+}
+
+impl Latency {
+    fn peer_mut(&mut self, kind: &PeerKind) -> &mut PeerLatency {
+        match kind {
+            PeerKind::Controller => &mut self.controller,
+            PeerKind::Instrument => &mut self.instrument,
+        }
+    }
+
+    fn monitoring(&self, config: &Config) -> bool {
+        self.monitor.unwrap_or(config.latency.monitor)
+    }
+}
+
+/// Where probes, reports and alerts go.
+struct Peers {
+    controller: Arc<Peer>,
+    instrument: Arc<Peer>,
+}
+
+impl Peers {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            controller: Arc::new(config.controller.clone()),
+            instrument: Arc::new(config.instrument.clone()),
+        }
+    }
+
+    fn from_labeled(labeled: &LabeledMessage) -> Self {
+        Self {
+            controller: labeled.controller(),
+            instrument: labeled.instrument(),
+        }
+    }
+
+    fn to(&self, kind: &PeerKind, message: osc::Message) -> LabeledMessage {
+        match kind {
+            PeerKind::Controller => {
+                LabeledMessage::new(self.instrument.clone(), self.controller.clone(), message)
+            }
+            PeerKind::Instrument => {
+                LabeledMessage::new(self.controller.clone(), self.instrument.clone(), message)
+            }
+        }
+    }
+}
+
+fn latency() -> &'static Mutex<Latency> {
+    static LATENCY: OnceLock<Mutex<Latency>> = OnceLock::new();
+    LATENCY.get_or_init(|| Mutex::new(Latency::default()))
+}
+
+/// Probes both peers. Each peer's window is reported as its reply arrives.
+pub(super) fn query(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let peers = Peers::from_labeled(&labeled);
+    let mut latency = lock()?;
+    let mut messages = expire(&config, &mut latency, &peers);
+    messages.extend(probes(&config, &mut latency, &peers, true));
+    Ok(messages)
+}
+
+/// Switches the background monitor on or off, or toggles it without an argument.
+pub(super) fn monitor(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut latency = lock()?;
+    let on = match labeled.message.args.first() {
+        Some(OscType::Bool(on)) => *on,
+        Some(value) => osc::as_f32(value).is_some_and(|value| value >= 0.5),
+        None => !latency.monitoring(&config),
+    };
+    latency.monitor = Some(on);
+    debug!("Latency monitor switched {}", if on { "on" } else { "off" });
+    Ok(vec![build_return_message(
+        labeled,
+        String::from("/sys/latency/monitor"),
+        OscType::Bool(on),
+    )])
+}
+
+/// Answers a ping from a peer, so another arcflash can measure its latency to us.
+pub(super) fn ping(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut reply =
+        build_return_message(labeled.clone(), String::from("/sys/pong"), OscType::Int(0));
+    reply.message.args = labeled.message.args;
+    Ok(vec![reply])
+}
+
+/// Completes the probe a pong answers. Pongs for lost probes are ignored.
+pub(super) fn pong(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let Some(OscType::Int(id)) = labeled.message.args.first() else {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            "Pong without a probe id.",
+        ));
+    };
+    let peers = Peers::from_labeled(&labeled);
+    let mut latency = lock()?;
+    Ok(complete(
+        &config,
+        &mut latency,
+        &peers,
+        &labeled.peer_recv.kind,
+        Some(*id),
+    ))
+}
+
+/// Completes a probe of the instrument when the value of the queried parameter arrives, and
+/// returns the report and alert for the controller. The reply can't be told apart from a
+/// change made on the instrument, so it is left to go on to the controller as usual.
+pub(crate) fn probe_reply(
+    config: &Config,
+    labeled: &LabeledMessage,
+) -> Option<Vec<LabeledMessage>> {
+    if labeled.peer_recv.kind != PeerKind::Instrument
+        || config.latency.instrument_probe != ProbeKind::Query
+        || labeled.message.addr != config.latency.probe_param
+    {
+        return None;
+    }
+    let mut latency = lock().ok()?;
+    latency.instrument.pending.as_ref()?;
+    let peers = Peers::from_labeled(labeled);
+    Some(complete(
+        config,
+        &mut latency,
+        &peers,
+        &PeerKind::Instrument,
+        None,
+    ))
+}
+
+/// Expires lost probes and, while the monitor is on, probes both peers every interval.
+pub(crate) fn start(config: Arc<Config>) {
+    let peers = Peers::from_config(&config);
+    let interval = Duration::from_secs_f32(config.latency.interval.max(TICK.as_secs_f32()));
+    std::thread::spawn(move || loop {
+        std::thread::sleep(TICK);
+        let messages = match latency().lock() {
+            Ok(mut latency) => {
+                let mut messages = expire(&config, &mut latency, &peers);
+                let due = !matches!(latency.last_probe, Some(last) if last.elapsed() < interval);
+                if latency.monitoring(&config) && due {
+                    messages.extend(probes(&config, &mut latency, &peers, false));
+                }
+                messages
+            }
+            Err(_) => {
+                warn!("Latency lock was poisoned, monitor stopped.");
+                return;
+            }
+        };
+        for message in messages {
+            if let Err(e) = send_message(message.message, message.peer_send) {
+                warn!("Failed to send latency probe: {}", e);
+            }
+        }
+    });
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> io::Result<MutexGuard<'static, Latency>> {
+    latency()
+        .lock()
+        .map_err(|_| Error::other("Latency lock was poisoned."))
+}
+
+/// Probes each peer that doesn't have a probe on its way already.
+fn probes(
+    config: &Config,
+    latency: &mut Latency,
+    peers: &Peers,
+    report: bool,
+) -> Vec<LabeledMessage> {
+    let mut messages = vec![];
+    for kind in [PeerKind::Controller, PeerKind::Instrument] {
+        if let Some(probe) = latency.peer_mut(&kind).pending.as_mut() {
+            probe.report |= report;
+            continue;
+        }
+        let id = latency.next_id;
+        latency.next_id = latency.next_id.wrapping_add(1);
+        latency.peer_mut(&kind).pending = Some(Probe {
+            id,
+            sent: Instant::now(),
+            report,
+        });
+        let message = match (&kind, config.latency.instrument_probe) {
+            (PeerKind::Instrument, ProbeKind::Query) => {
+                osc::msg(format!("/q{}", config.latency.probe_param), vec![])
+            }
+            _ => osc::msg("/sys/ping", vec![OscType::Int(id)]),
+        };
+        messages.push(peers.to(&kind, message));
+    }
+    latency.last_probe = Some(Instant::now());
+    messages
+}
+
+/// Adds the round trip of the pending probe to the window of the peer.
+fn complete(
+    config: &Config,
+    latency: &mut Latency,
+    peers: &Peers,
+    kind: &PeerKind,
+    id: Option<i32>,
+) -> Vec<LabeledMessage> {
+    let monitoring = latency.monitoring(config);
+    let peer = latency.peer_mut(kind);
+    let Some(probe) = peer.take_pending(|probe| id.is_none() || id == Some(probe.id)) else {
+        debug!("Ignoring reply to a lost latency probe.");
+        return vec![];
+    };
+    let round_trip = probe.sent.elapsed();
+    if peer.samples.len() >= config.latency.window.max(1) {
+        peer.samples.pop_front();
+    }
+    peer.samples.push_back(round_trip);
+    debug!("Round trip to {}: {:?}", kind, round_trip);
+
+    let mut messages = vec![];
+    if probe.report {
+        messages.push(peers.to(&PeerKind::Controller, report(kind, peer)));
+    }
+    let millis = millis(round_trip);
+    if monitoring && millis > config.latency.threshold_ms {
+        warn!("Round trip to {} took {:.1} ms.", kind, millis);
+        messages.push(peers.to(
+            &PeerKind::Controller,
+            osc::msg(
+                "/sys/latency/alert",
+                vec![OscType::String(peer_name(kind)), OscType::Float(millis)],
+            ),
+        ));
+    }
+    messages
+}
+
+/// Counts probes that were not answered in time as lost.
+fn expire(config: &Config, latency: &mut Latency, peers: &Peers) -> Vec<LabeledMessage> {
+    let monitoring = latency.monitoring(config);
+    let mut messages = vec![];
+    for kind in [PeerKind::Controller, PeerKind::Instrument] {
+        let peer = latency.peer_mut(&kind);
+        let Some(probe) = peer.take_pending(|probe| probe.sent.elapsed() > PROBE_TIMEOUT) else {
+            continue;
+        };
+        peer.lost += 1;
+        debug!("Latency probe to {} was lost.", kind);
+        if probe.report {
+            messages.push(peers.to(&PeerKind::Controller, report(&kind, peer)));
+        }
+        if monitoring {
+            messages.push(peers.to(
+                &PeerKind::Controller,
+                osc::msg(
+                    "/sys/latency/timeout",
+                    vec![OscType::String(peer_name(&kind))],
+                ),
+            ));
+        }
+    }
+    messages
+}
+
+/// Min, average, max and jitter of the window in milliseconds, and the probes lost.
+/// Jitter is the average difference between consecutive round trips.
+fn report(kind: &PeerKind, peer: &PeerLatency) -> osc::Message {
+    let samples: Vec<f32> = peer.samples.iter().copied().map(millis).collect();
+    let count = samples.len().max(1) as f32;
+    let min = samples.iter().copied().reduce(f32::min).unwrap_or_default();
+    let max = samples.iter().copied().reduce(f32::max).unwrap_or_default();
+    let avg = samples.iter().fold(0.0, |sum, sample| sum + sample) / count;
+    let jitter = samples
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .fold(0.0, |sum, difference| sum + difference)
+        / (samples.len().saturating_sub(1).max(1) as f32);
+    osc::msg(
+        format!("/sys/latency/{}", peer_name(kind)),
+        vec![
+            OscType::Float(min),
+            OscType::Float(avg),
+            OscType::Float(max),
+            OscType::Float(jitter),
+            OscType::Int(peer.lost.min(i32::MAX as u64) as i32),
+        ],
+    )
+}
+
+fn millis(duration: Duration) -> f32 {
+    duration.as_secs_f32() * 1000.0
+}
+
+fn peer_name(kind: &PeerKind) -> String {
+    kind.to_string().to_lowercase()
+}
@@ -0,0 +1,270 @@
+use crate::{
+    config::{data_dir, Config},
+    extension::param_store,
+    labeler::LabeledMessage,
+    osc,
+};
+use log::{debug, info, warn};
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{self, Error},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+};
+
+use super::build_return_message;
+
+/// Controller addresses bound to instrument parameters, as stored in the bindings file.
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct Bindings {
+    #[serde(default, rename = "binding")]
+    bindings: Vec<Binding>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct Binding {
+    controller: String,
+    param: String,
+}
+
+/// Learning takes two steps: first the controller control is moved, then the
+/// instrument parameter is touched and echoed back by the instrument.
+#[derive(Debug, Default, PartialEq)]
+enum LearnState {
+    #[default]
+    Idle,
+    WaitingForController,
+    WaitingForParam(String),
+}
+
+#[derive(Debug, Default)]
+struct Learn {
+    state: LearnState,
+    bindings: Option<Bindings>,
+    /// Parameter values when learning started, so only a parameter that was moved binds.
+    baseline: BTreeMap<String, OscType>,
+}
+
+fn learn() -> &'static Mutex<Learn> {
+    static LEARN: OnceLock<Mutex<Learn>> = OnceLock::new();
+    LEARN.get_or_init(|| Mutex::new(Learn::default()))
+}
+
+/// Rewrites a controller message on a bound address to the instrument parameter.
+/// While learning, remembers which controller address was moved last.
+pub(crate) fn to_instrument(config: &Config, labeled: &mut LabeledMessage) {
+    let Ok(mut learn) = learn().lock() else {
+        return;
+    };
+    if learn.state != LearnState::Idle {
+        debug!(
+            "Learn mode picked up controller address {}",
+            labeled.message.addr
+        );
+        learn.state = LearnState::WaitingForParam(labeled.message.addr.clone());
+        return;
+    }
+    let bindings = loaded_bindings(config, &mut learn);
+    if let Some(binding) = bindings
+        .iter()
+        .find(|b| b.controller == labeled.message.addr)
+    {
+        labeled.message.addr = binding.param.clone();
+    }
+}
+
+/// Rewrites an instrument parameter message to the controller address bound to it.
+/// While learning, the first parameter the instrument reports with a value other than the
+/// one it had when learning started completes the binding, and a confirmation for the
+/// controller is returned. A parameter that wasn't known yet has to change once more.
+pub(crate) fn to_controller(
+    config: &Config,
+    labeled: &mut LabeledMessage,
+) -> Option<LabeledMessage> {
+    if !labeled.message.addr.starts_with("/param/") {
+        return None;
+    }
+    let mut learn = learn().lock().ok()?;
+    if let LearnState::WaitingForParam(controller) = &learn.state {
+        let controller = controller.clone();
+        let addr = &labeled.message.addr;
+        let value = labeled.message.args.first().cloned()?;
+        match learn.baseline.insert(addr.clone(), value.clone()) {
+            Some(before) if before != value => {}
+            _ => {
+                debug!("Learn mode ignores {}, it didn't change.", addr);
+                return None;
+            }
+        }
+        let binding = Binding {
+            controller,
+            param: labeled.message.addr.clone(),
+        };
+        learn.state = LearnState::Idle;
+        learn.baseline.clear();
+        add_binding(config, &mut learn, binding.clone());
+        return Some(LabeledMessage::new(
+            labeled.controller(),
+            labeled.controller(),
+            osc::msg(
+                "/sys/learn/bound",
+                vec![
+                    OscType::String(binding.controller),
+                    OscType::String(binding.param),
+                ],
+            ),
+        ));
+    }
+    let bindings = loaded_bindings(config, &mut learn);
+    if let Some(binding) = bindings.iter().find(|b| b.param == labeled.message.addr) {
+        labeled.message.addr = binding.controller.clone();
+    }
+    None
+}
+
+/// Starts learn mode, the next controller control and instrument parameter get bound.
+pub(super) fn start(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut learn = lock()?;
+    learn.state = LearnState::WaitingForController;
+    learn.baseline = param_store::all();
+    drop(learn);
+    info!("Learn mode started.");
+    Ok(vec![build_return_message(
+        labeled,
+        String::from("/sys/learn/start"),
+        OscType::Bool(true),
+    )])
+}
+
+/// Leaves learn mode without binding anything.
+pub(super) fn stop(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut learn = lock()?;
+    learn.state = LearnState::Idle;
+    learn.baseline.clear();
+    drop(learn);
+    Ok(vec![build_return_message(
+        labeled,
+        String::from("/sys/learn/stop"),
+        OscType::Bool(true),
+    )])
+}
+
+/// Sends the number of bindings and each binding to the controller.
+pub(super) fn list(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut learn = lock()?;
+    let bindings = loaded_bindings(&config, &mut learn).to_vec();
+    let mut messages = vec![build_return_message(
+        labeled.clone(),
+        String::from("/sys/learn/count"),
+        OscType::Int(bindings.len() as i32),
+    )];
+    for (i, binding) in bindings.into_iter().enumerate() {
+        let mut message = build_return_message(
+            labeled.clone(),
+            format!("/sys/learn/binding/{}", i + 1),
+            OscType::String(binding.controller),
+        );
+        message.message.args.push(OscType::String(binding.param));
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Removes the binding for the controller address or parameter given as first argument,
+/// or all bindings when no argument is given.
+pub(super) fn clear(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let addr = labeled
+        .message
+        .args
+        .first()
+        .and_then(|arg| arg.clone().string());
+    let mut learn = lock()?;
+    loaded_bindings(&config, &mut learn);
+    let bindings = learn.bindings.get_or_insert_with(Bindings::default);
+    let before = bindings.bindings.len();
+    match &addr {
+        Some(addr) => bindings
+            .bindings
+            .retain(|b| &b.controller != addr && &b.param != addr),
+        None => bindings.bindings.clear(),
+    }
+    let removed = before - bindings.bindings.len();
+    save_bindings(&config, bindings)?;
+
+    Ok(vec![build_return_message(
+        labeled,
+        String::from("/sys/learn/clear"),
+        OscType::Int(removed as i32),
+    )])
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> io::Result<MutexGuard<'static, Learn>> {
+    learn()
+        .lock()
+        .map_err(|_| Error::other("Learn lock was poisoned."))
+}
+
+/// Bindings are read from file the first time they are needed.
+fn loaded_bindings<'a>(config: &Config, learn: &'a mut Learn) -> &'a [Binding] {
+    if learn.bindings.is_none() {
+        learn.bindings = Some(read_bindings(config).unwrap_or_else(|e| {
+            debug!("No bindings loaded: {}", e);
+            Bindings::default()
+        }));
+    }
+    learn
+        .bindings
+        .as_ref()
+        .map(|b| b.bindings.as_slice())
+        .unwrap_or_default()
+}
+
+/// A controller address and a parameter can each only be bound once.
+fn add_binding(config: &Config, learn: &mut Learn, binding: Binding) {
+    loaded_bindings(config, learn);
+    let bindings = learn.bindings.get_or_insert_with(Bindings::default);
+    bindings
+        .bindings
+        .retain(|b| b.controller != binding.controller && b.param != binding.param);
+    info!("Bound {} to {}", binding.controller, binding.param);
+    bindings.bindings.push(binding);
+    if let Err(e) = save_bindings(config, bindings) {
+        warn!("Failed to save bindings: {}", e);
+    }
+}
+
+fn read_bindings(config: &Config) -> io::Result<Bindings> {
+    let path = data_dir(&config.options, &config.options.bindings_file)?;
+    let contents = std::fs::read_to_string(&path)?;
+    toml::from_str::<Bindings>(&contents).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to read bindings {:?}: {}", path, e),
+        )
+    })
+}
+
+fn save_bindings(config: &Config, bindings: &Bindings) -> io::Result<()> {
+    let path = data_dir(&config.options, &config.options.bindings_file)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let contents = toml::to_string(bindings).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to serialize bindings: {}", e),
+        )
+    })?;
+    std::fs::write(path, contents)
+}
@@ -0,0 +1,282 @@
+use crate::{config::Config, labeler::LabeledMessage, osc};
+use log::{debug, info, warn};
+use rosc::OscType;
+use std::{
+    collections::BTreeMap,
+    io::{self, Error},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use super::build_return_message;
+
+/// All patches found in the configured library directories, grouped by category.
+/// A patch is identified by its position in `patches`, which is sorted by category and name.
+#[derive(Debug, Default)]
+struct Library {
+    categories: Vec<String>,
+    patches: Vec<LibraryPatch>,
+}
+
+#[derive(Debug)]
+struct LibraryPatch {
+    category: String,
+    name: String,
+    path: PathBuf,
+}
+
+impl Library {
+    fn in_category(&self, category: &str) -> Vec<(usize, &LibraryPatch)> {
+        self.patches
+            .iter()
+            .enumerate()
+            .filter(|(_, patch)| patch.category == category)
+            .collect()
+    }
+}
+
+/// The library is indexed on first use and kept until a rescan is requested.
+fn library() -> &'static Mutex<Option<Arc<Library>>> {
+    static LIBRARY: OnceLock<Mutex<Option<Arc<Library>>>> = OnceLock::new();
+    LIBRARY.get_or_init(|| Mutex::new(None))
+}
+
+/// Sends the number of categories and the name of each category to the controller.
+pub(super) fn list_categories(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let library = get_library(&config, false)?;
+    let mut messages = vec![build_return_message(
+        labeled.clone(),
+        String::from("/sys/library/categories"),
+        OscType::Int(library.categories.len() as i32),
+    )];
+    for (i, category) in library.categories.iter().enumerate() {
+        messages.push(build_return_message(
+            labeled.clone(),
+            format!("/sys/library/category/{}", i + 1),
+            OscType::String(category.clone()),
+        ));
+    }
+    Ok(messages)
+}
+
+/// Sends one page of patch names and ids for a category to the controller.
+/// The category is given by name or number, pages are numbered from 1. Unused slots on the
+/// last page are cleared so the controller doesn't show stale names.
+pub(super) fn list_page(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let library = get_library(&config, false)?;
+    let page_size = config.library.page_size.max(1);
+
+    let category = match labeled.message.args.first() {
+        Some(OscType::String(name)) => name.clone(),
+        Some(arg) => {
+            let number = osc::as_i64(arg).unwrap_or_default();
+            library
+                .categories
+                .get((number as usize).wrapping_sub(1))
+                .cloned()
+                .unwrap_or_default()
+        }
+        None => String::new(),
+    };
+    if !library.categories.contains(&category) {
+        return Err(Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown library category '{}'.", category),
+        ));
+    }
+
+    let patches = library.in_category(&category);
+    let pages = patches.len().div_ceil(page_size).max(1);
+    let page = labeled
+        .message
+        .args
+        .get(1)
+        .and_then(osc::as_i64)
+        .unwrap_or(1)
+        .clamp(1, pages as i64) as usize;
+    debug!("Library page {} of {} for {}", page, pages, category);
+
+    let mut page_message = build_return_message(
+        labeled.clone(),
+        String::from("/sys/library/page"),
+        OscType::String(category.clone()),
+    );
+    page_message
+        .message
+        .args
+        .extend([OscType::Int(page as i32), OscType::Int(pages as i32)]);
+    let mut messages = vec![page_message];
+
+    let mut slots = patches.iter().skip((page - 1) * page_size);
+    for slot in 1..=page_size {
+        let (name, id) = match slots.next() {
+            Some((id, patch)) => (patch.name.clone(), *id as i32),
+            None => (String::new(), -1),
+        };
+        messages.push(build_return_message(
+            labeled.clone(),
+            format!("/sys/library/label/{}", slot),
+            OscType::String(name),
+        ));
+        messages.push(build_return_message(
+            labeled.clone(),
+            format!("/sys/library/id/{}", slot),
+            OscType::Int(id),
+        ));
+    }
+    Ok(messages)
+}
+
+/// Asks the instrument to load the library patch with the id given as first argument.
+pub(super) fn load_patch(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let library = get_library(&config, false)?;
+    let patch = labeled
+        .message
+        .args
+        .first()
+        .and_then(osc::as_i64)
+        .and_then(|id| library.patches.get(usize::try_from(id).ok()?))
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                "First argument is not a known library patch id.",
+            )
+        })?;
+    debug!("Loading library patch {:?}", patch.path);
+
+    // Like patchbays, the instrument gets the path without extension.
+    let load_message = LabeledMessage::new(
+        labeled.peer_recv.clone(),
+        labeled.instrument(),
+        osc::msg(
+            "/patch/load",
+            vec![OscType::String(
+                patch.path.with_extension("").to_string_lossy().to_string(),
+            )],
+        ),
+    );
+    let reply = build_return_message(
+        labeled,
+        String::from("/sys/library/loaded"),
+        OscType::String(patch.name.clone()),
+    );
+    Ok(vec![load_message, reply])
+}
+
+/// Indexes the library directories again, for when patches were added or removed.
+pub(super) fn rescan(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let library = get_library(&config, true)?;
+    Ok(vec![build_return_message(
+        labeled,
+        String::from("/sys/library/rescan"),
+        OscType::Int(library.patches.len() as i32),
+    )])
+}
+
+// ********
+// Helpers
+// ********
+
+fn get_library(config: &Config, rescan: bool) -> io::Result<Arc<Library>> {
+    let mut cached = library()
+        .lock()
+        .map_err(|_| Error::other("Library lock was poisoned."))?;
+    if rescan || cached.is_none() {
+        *cached = Some(Arc::new(index_library(&config.library.paths)));
+    }
+    Ok(cached.clone().unwrap_or_default())
+}
+
+fn index_library(roots: &[PathBuf]) -> Library {
+    let mut by_category: BTreeMap<String, Vec<LibraryPatch>> = BTreeMap::new();
+    for root in roots {
+        let mut files = vec![];
+        if let Err(e) = find_patches(root, &mut files) {
+            warn!("Unable to index patch library {:?}: {}", root, e);
+            continue;
+        }
+        for path in files {
+            let category = category_for(root, &path);
+            let Some(name) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+                continue;
+            };
+            by_category
+                .entry(category.clone())
+                .or_default()
+                .push(LibraryPatch {
+                    category,
+                    name,
+                    path,
+                });
+        }
+    }
+
+    let mut library = Library::default();
+    for (category, mut patches) in by_category {
+        patches.sort_by_key(|patch| patch.name.to_lowercase());
+        library.categories.push(category);
+        library.patches.extend(patches);
+    }
+    info!(
+        "Indexed {} library patches in {} categories",
+        library.patches.len(),
+        library.categories.len()
+    );
+    library
+}
+
+/// Recursively collects all .fxp files below a directory.
+fn find_patches(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)?.filter_map(Result::ok) {
+        let path = entry.path();
+        // The file type doesn't follow symlinks, so a link back up the tree can't loop.
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            // One folder we can't read shouldn't hide the rest of the library.
+            if let Err(e) = find_patches(&path, files) {
+                warn!("Skipping library folder {:?}: {}", path, e);
+            }
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("fxp"))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The category is the folder a patch is in, relative to the library directory.
+fn category_for(root: &Path, patch: &Path) -> String {
+    let relative = patch
+        .parent()
+        .and_then(|parent| parent.strip_prefix(root).ok())
+        .map(|dir| {
+            dir.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .unwrap_or_default();
+    match relative.is_empty() {
+        true => root
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        false => relative,
+    }
+}
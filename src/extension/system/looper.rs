@@ -0,0 +1,196 @@
+use crate::{
+    config::{Config, LooperConfig},
+    extension::param_store,
+    labeler::LabeledMessage,
+    osc,
+    peer::Peer,
+    sender::send_message,
+};
+use log::{debug, warn};
+use rosc::OscType;
+use std::{
+    collections::HashMap,
+    io::{self, Error},
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::build_return_message;
+
+/// A loop slot holds a timed sequence of controller parameter changes.
+#[derive(Default)]
+struct Slot {
+    events: Vec<(Duration, osc::Message)>,
+    length: Duration,
+    recording_since: Option<Instant>,
+    /// Set to stop the playback thread of this slot. The thread sends while holding the
+    /// lock, so once it is set nothing more is sent.
+    playing: Option<Arc<Mutex<bool>>>,
+}
+
+impl Slot {
+    fn state(&self) -> &'static str {
+        match (&self.recording_since, &self.playing) {
+            (Some(_), _) => "recording",
+            (None, Some(_)) => "playing",
+            (None, None) if self.events.is_empty() => "empty",
+            (None, None) => "stopped",
+        }
+    }
+
+    fn stop_playing(&mut self) {
+        if let Some(stop) = self.playing.take() {
+            *stop.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        }
+    }
+
+    /// Ends the recording and works out the loop length, rounded to whole bars if a
+    /// tempo is configured.
+    fn finish_recording(&mut self, looper: &LooperConfig) {
+        let Some(since) = self.recording_since.take() else {
+            return;
+        };
+        let recorded = since.elapsed();
+        self.length = match looper.tempo {
+            Some(tempo) if tempo > 0.0 => {
+                let bar = Duration::from_secs_f32(60.0 / tempo * looper.beats_per_bar as f32);
+                let bars = (recorded.as_secs_f32() / bar.as_secs_f32())
+                    .round()
+                    .max(1.0);
+                bar.mul_f32(bars)
+            }
+            _ => recorded,
+        };
+        let length = self.length;
+        self.events.retain(|(offset, _)| *offset < length);
+        debug!(
+            "Recorded {} events in a loop of {:?}",
+            self.events.len(),
+            self.length
+        );
+    }
+}
+
+fn slots() -> &'static Mutex<HashMap<String, Slot>> {
+    static SLOTS: OnceLock<Mutex<HashMap<String, Slot>>> = OnceLock::new();
+    SLOTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Adds a controller parameter change to every slot that is recording.
+pub(crate) fn record(message: &osc::Message) {
+    if !message.addr.starts_with("/param/") {
+        return;
+    }
+    let Ok(mut slots) = slots().lock() else {
+        return;
+    };
+    for slot in slots.values_mut() {
+        if let Some(since) = slot.recording_since {
+            slot.events.push((since.elapsed(), message.clone()));
+        }
+    }
+}
+
+/// Handles /sys/loop/<slot>/record, /stop, /play and /clear and reports the slot state.
+pub(super) fn loop_handler(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let addr = labeled.message.addr.clone();
+    let mut parts = addr
+        .split("/sys/loop/")
+        .nth(1)
+        .unwrap_or_default()
+        .split('/');
+    let (Some(name), Some(action)) = (parts.next(), parts.next()) else {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            "Use /sys/loop/<slot>/record, /stop, /play or /clear.",
+        ));
+    };
+
+    let mut slots = slots()
+        .lock()
+        .map_err(|_| Error::other("Looper lock was poisoned."))?;
+    let slot = slots.entry(name.to_string()).or_default();
+    match action {
+        "record" => {
+            slot.stop_playing();
+            slot.events.clear();
+            slot.recording_since = Some(Instant::now());
+        }
+        "stop" => {
+            slot.finish_recording(&config.looper);
+            slot.stop_playing();
+        }
+        "play" => {
+            slot.finish_recording(&config.looper);
+            if slot.playing.is_none() && !slot.events.is_empty() {
+                slot.playing = Some(start_playback(
+                    name,
+                    slot.events.clone(),
+                    slot.length,
+                    labeled.instrument(),
+                ));
+            }
+        }
+        "clear" => {
+            slot.stop_playing();
+            *slot = Slot::default();
+        }
+        other => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown loop action '{}'.", other),
+            ))
+        }
+    }
+    debug!("Loop slot {} is {}", name, slot.state());
+
+    Ok(vec![build_return_message(
+        labeled,
+        format!("/sys/loop/{}/state", name),
+        OscType::String(String::from(slot.state())),
+    )])
+}
+
+// ********
+// Helpers
+// ********
+
+/// Plays the events to the instrument over and over until the returned flag is set.
+fn start_playback(
+    name: &str,
+    events: Vec<(Duration, osc::Message)>,
+    length: Duration,
+    instrument: Arc<Peer>,
+) -> Arc<Mutex<bool>> {
+    let stop = Arc::new(Mutex::new(false));
+    let stop_thread = stop.clone();
+    let name = name.to_string();
+    let length = length.max(Duration::from_millis(10));
+
+    std::thread::spawn(move || {
+        let mut loop_start = Instant::now();
+        loop {
+            for (offset, message) in &events {
+                if let Some(wait) = (loop_start + *offset).checked_duration_since(Instant::now()) {
+                    std::thread::sleep(wait);
+                }
+                // Checked after waking, a stop during the wait sends nothing more.
+                let stopped = stop_thread.lock().unwrap_or_else(PoisonError::into_inner);
+                if *stopped {
+                    debug!("Loop slot {} stopped playing", name);
+                    return;
+                }
+                param_store::record(message);
+                if let Err(e) = send_message(message.clone(), instrument.clone()) {
+                    warn!("Loop slot {} failed to send: {}", name, e);
+                }
+                drop(stopped);
+            }
+            loop_start += length;
+        }
+    });
+    stop
+}
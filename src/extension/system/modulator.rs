@@ -0,0 +1,239 @@
+use crate::{
+    config::{Config, Shape},
+    extension::param_store,
+    labeler::LabeledMessage,
+    osc,
+    sender::send_message,
+};
+use log::{debug, info, warn};
+use rand::{rngs::ThreadRng, Rng};
+use rosc::OscType;
+use std::{
+    collections::BTreeMap,
+    f32::consts::TAU,
+    io::{self, Error},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant},
+};
+
+use super::build_return_message;
+
+/// How often the modulators send a new value to the instrument.
+const TICK: Duration = Duration::from_millis(50);
+/// Values closer than this to the value sent last are not sent again.
+const RESOLUTION: f32 = 0.0005;
+
+/// The running state of a modulator from the config.
+struct Generator {
+    param: String,
+    shape: Shape,
+    rate: f32,
+    depth: f32,
+    configured_center: Option<f32>,
+    center: f32,
+    enabled: bool,
+    phase: f32,
+    /// Current output of the random shapes, between -1 and 1.
+    held: f32,
+    last_sent: Option<f32>,
+}
+
+impl Generator {
+    fn switch_on(&mut self) {
+        self.center = self
+            .configured_center
+            .or_else(|| param_store::get(&self.param).as_ref().and_then(osc::as_f32))
+            .unwrap_or(0.5);
+        self.phase = 0.0;
+        self.held = 0.0;
+        self.last_sent = None;
+        self.enabled = true;
+    }
+
+    /// Moves the modulator on by `elapsed` and returns the new parameter value, if it
+    /// changed enough to be worth sending.
+    fn advance(&mut self, elapsed: f32, rng: &mut ThreadRng) -> Option<f32> {
+        let previous = self.phase;
+        self.phase = (self.phase + self.rate * elapsed).fract();
+        let wave = match self.shape {
+            Shape::Sine => (self.phase * TAU).sin(),
+            // Starts at the center and rises first, like the sine.
+            Shape::Triangle => 1.0 - 4.0 * ((self.phase + 0.25).fract() - 0.5).abs(),
+            Shape::RandomWalk => {
+                let step = rng.gen_range(-1.0..=1.0) * self.rate * elapsed * 4.0;
+                self.held = (self.held + step).clamp(-1.0, 1.0);
+                self.held
+            }
+            Shape::SampleAndHold => {
+                if self.phase < previous {
+                    self.held = rng.gen_range(-1.0..=1.0);
+                }
+                self.held
+            }
+        };
+        let value = (self.center + self.depth * wave).clamp(0.0, 1.0);
+        if self
+            .last_sent
+            .is_some_and(|last| (last - value).abs() < RESOLUTION)
+        {
+            return None;
+        }
+        self.last_sent = Some(value);
+        Some(value)
+    }
+}
+
+fn generators() -> &'static Mutex<BTreeMap<String, Generator>> {
+    static GENERATORS: OnceLock<Mutex<BTreeMap<String, Generator>>> = OnceLock::new();
+    GENERATORS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Sets up the modulators from the config and starts the thread that runs them.
+pub(crate) fn start(config: Arc<Config>) {
+    if config.modulators.is_empty() {
+        return;
+    }
+    let Ok(mut running) = generators().lock() else {
+        return;
+    };
+    for modulator in &config.modulators {
+        let mut generator = Generator {
+            param: modulator.param.clone(),
+            shape: modulator.shape,
+            rate: modulator.rate.max(0.0),
+            depth: modulator.depth,
+            configured_center: modulator.center,
+            center: modulator.center.unwrap_or(0.5),
+            enabled: false,
+            phase: 0.0,
+            held: 0.0,
+            last_sent: None,
+        };
+        if modulator.enabled {
+            generator.switch_on();
+        }
+        running.insert(modulator.name.clone(), generator);
+    }
+    info!("Starting {} modulators.", running.len());
+    drop(running);
+
+    let instrument = Arc::new(config.instrument.clone());
+    std::thread::spawn(move || {
+        let mut rng = rand::thread_rng();
+        let mut last_tick = Instant::now();
+        loop {
+            std::thread::sleep(TICK);
+            let elapsed = last_tick.elapsed().as_secs_f32();
+            last_tick = Instant::now();
+
+            let messages: Vec<osc::Message> = match generators().lock() {
+                Ok(mut generators) => generators
+                    .values_mut()
+                    .filter(|generator| generator.enabled)
+                    .filter_map(|generator| {
+                        generator
+                            .advance(elapsed, &mut rng)
+                            .map(|value| osc::msg(&generator.param, vec![OscType::Float(value)]))
+                    })
+                    .collect(),
+                Err(_) => {
+                    warn!("Modulator lock was poisoned, modulators stopped.");
+                    return;
+                }
+            };
+            for message in messages {
+                param_store::record(&message);
+                if let Err(e) = send_message(message, instrument.clone()) {
+                    warn!("Modulator failed to send: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Handles /sys/mod/<name>/depth, /rate and /on. Without an argument the current setting
+/// is reported and /on toggles the modulator. Switching a modulator off puts the
+/// parameter back at its center.
+pub(super) fn mod_handler(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let addr = labeled.message.addr.clone();
+    let mut parts = addr
+        .split("/sys/mod/")
+        .nth(1)
+        .unwrap_or_default()
+        .split('/');
+    let (Some(name), Some(control)) = (parts.next(), parts.next()) else {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            "Use /sys/mod/<name>/depth, /rate or /on.",
+        ));
+    };
+    let argument = labeled.message.args.first().cloned();
+
+    let mut generators = lock()?;
+    let generator = generators.get_mut(name).ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown modulator '{}'.", name),
+        )
+    })?;
+
+    let mut messages = vec![];
+    let reply = match control {
+        "depth" => {
+            if let Some(depth) = argument.as_ref().and_then(osc::as_f32) {
+                generator.depth = depth;
+            }
+            OscType::Float(generator.depth)
+        }
+        "rate" => {
+            if let Some(rate) = argument.as_ref().and_then(osc::as_f32) {
+                generator.rate = rate.max(0.0);
+            }
+            OscType::Float(generator.rate)
+        }
+        "on" => {
+            let on = match argument {
+                Some(OscType::Bool(on)) => on,
+                Some(value) => osc::as_f32(&value).is_some_and(|value| value >= 0.5),
+                None => !generator.enabled,
+            };
+            if on && !generator.enabled {
+                generator.switch_on();
+            } else if !on && generator.enabled {
+                generator.enabled = false;
+                let message = osc::msg(&generator.param, vec![OscType::Float(generator.center)]);
+                param_store::record(&message);
+                messages.push(LabeledMessage::new(
+                    labeled.controller(),
+                    labeled.instrument(),
+                    message,
+                ));
+            }
+            OscType::Bool(generator.enabled)
+        }
+        other => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown modulator control '{}'.", other),
+            ))
+        }
+    };
+    debug!("Modulator {} {} is {:?}", name, control, reply);
+
+    messages.push(build_return_message(
+        labeled,
+        format!("/sys/mod/{}/{}", name, control),
+        reply,
+    ));
+    Ok(messages)
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> io::Result<MutexGuard<'static, BTreeMap<String, Generator>>> {
+    generators()
+        .lock()
+        .map_err(|_| Error::other("Modulator lock was poisoned."))
+}
@@ -0,0 +1,434 @@
+use crate::config::{Chord, Config, Curve, NotesConfig, Scale};
+use crate::{labeler::LabeledMessage, osc, sender::send_message};
+use log::{debug, info, warn};
+use rosc::OscType;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Error},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant},
+};
+
+use super::build_return_message;
+
+const KEY_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The note input settings the controller can change, and the notes each controller
+/// key or pad is holding.
+struct NoteInput {
+    scale: Scale,
+    key: u8,
+    octave: i32,
+    chord: Chord,
+    held: HashMap<String, Vec<u8>>,
+    last_seen: Instant,
+    /// Whether the watchdog already released everything since the controller was last seen.
+    released: bool,
+}
+
+fn note_input(config: &Config) -> &'static Mutex<NoteInput> {
+    static INPUT: OnceLock<Mutex<NoteInput>> = OnceLock::new();
+    INPUT.get_or_init(|| {
+        let notes = notes_config(config);
+        let key = parse_key(&notes.key).unwrap_or_else(|| {
+            warn!("Unknown key '{}', using C.", notes.key);
+            0
+        });
+        Mutex::new(NoteInput {
+            scale: notes.scale,
+            key,
+            octave: notes.octave,
+            chord: notes.chord,
+            held: HashMap::new(),
+            last_seen: Instant::now(),
+            released: false,
+        })
+    })
+}
+
+/// Every note Arcflash started and hasn't released, with the number of times it was started.
+fn sounding() -> &'static Mutex<BTreeMap<u8, u32>> {
+    static SOUNDING: OnceLock<Mutex<BTreeMap<u8, u32>>> = OnceLock::new();
+    SOUNDING.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Keeps track of a /mnote message Arcflash sends to the instrument.
+pub(crate) fn track(message: &osc::Message) {
+    if message.addr != "/mnote" {
+        return;
+    }
+    let mut args = message.args.iter().filter_map(osc::as_f32);
+    let (Some(note), Some(velocity)) = (args.next(), args.next()) else {
+        return;
+    };
+    let Ok(mut sounding) = sounding().lock() else {
+        return;
+    };
+    let note = note as u8;
+    if velocity > 0.0 {
+        *sounding.entry(note).or_default() += 1;
+    } else if let Some(count) = sounding.get_mut(&note) {
+        *count -= 1;
+        if *count == 0 {
+            sounding.remove(&note);
+        }
+    }
+}
+
+/// Remembers when the controller was last heard from.
+pub(crate) fn controller_seen(config: &Config) {
+    if let Ok(mut input) = note_input(config).lock() {
+        input.last_seen = Instant::now();
+        input.released = false;
+    }
+}
+
+/// Turns a controller note or pad message into /mnote messages for the instrument.
+/// Notes are played on `<address> <note> <velocity>`, `<address>/<note> <velocity>` and
+/// `<address>/xy <x> <y>`, a velocity of 0 or `<address>/xy/z 0` releases them. Returns
+/// `None` for messages that are not addressed to the note input, or without a `[notes]`
+/// section.
+pub(crate) fn play(config: &Config, labeled: &LabeledMessage) -> Option<Vec<LabeledMessage>> {
+    let notes_config = config.notes.as_ref()?;
+    let rest = labeled
+        .message
+        .addr
+        .strip_prefix(notes_config.address.as_str())?;
+    let args: Vec<f32> = labeled
+        .message
+        .args
+        .iter()
+        .filter_map(osc::as_f32)
+        .collect();
+    let (key, note, velocity) = match rest {
+        "" => {
+            let note = *args.first()?;
+            (format!("{}", note as u8), note, *args.get(1)?)
+        }
+        "/xy" => {
+            let (low, high) = (notes_config.xy_low as f32, notes_config.xy_high as f32);
+            let note = low + (high - low) * args.first()?.clamp(0.0, 1.0);
+            (
+                String::from("xy"),
+                note,
+                args.get(1).copied().unwrap_or(1.0),
+            )
+        }
+        "/xy/z" => {
+            // A touch on an XY pad doesn't play anything by itself, only letting go does.
+            if args.first().copied().unwrap_or(0.0) > 0.0 {
+                return Some(vec![]);
+            }
+            (String::from("xy"), 0.0, 0.0)
+        }
+        _ => {
+            let note = rest.strip_prefix('/')?.parse::<u8>().ok()? as f32;
+            (
+                format!("{}", note as u8),
+                note,
+                args.first().copied().unwrap_or(0.0),
+            )
+        }
+    };
+
+    let Ok(mut input) = note_input(config).lock() else {
+        return Some(vec![]);
+    };
+    let notes = match velocity > 0.0 {
+        true => input.notes_for(note),
+        false => vec![],
+    };
+    // Sliding over an XY pad only plays a new note when the quantized note changes.
+    if !notes.is_empty() && input.held.get(&key) == Some(&notes) {
+        return Some(vec![]);
+    }
+
+    let mut messages = vec![];
+    if let Some(previous) = input.held.remove(&key) {
+        messages.extend(previous.into_iter().map(|note| note_message(note, 0)));
+    }
+    if !notes.is_empty() {
+        let velocity = shape_velocity(velocity, notes_config.velocity_curve);
+        messages.extend(notes.iter().map(|note| note_message(*note, velocity)));
+        debug!("Note input {} plays {:?} at {}", key, notes, velocity);
+        input.held.insert(key, notes);
+    }
+
+    Some(
+        messages
+            .into_iter()
+            .map(|message| {
+                track(&message);
+                LabeledMessage::new(labeled.peer_recv.clone(), labeled.instrument(), message)
+            })
+            .collect(),
+    )
+}
+
+/// Handles /sys/notes/scale, /key, /octave and /chord. Without an argument the current
+/// setting is reported.
+pub(super) fn settings_handler(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let addr = labeled.message.addr.clone();
+    let setting = addr.split("/sys/notes/").nth(1).unwrap_or_default();
+    let argument = labeled.message.args.first().cloned();
+    let invalid = |value: &str| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} '{}'.", setting, value),
+        )
+    };
+
+    let mut input = lock(&config)?;
+    let reply = match (setting, argument) {
+        ("scale", Some(OscType::String(name))) => {
+            input.scale = parse_setting(&name).ok_or_else(|| invalid(&name))?;
+            setting_name(input.scale)
+        }
+        ("scale", _) => setting_name(input.scale),
+        ("chord", Some(OscType::String(name))) => {
+            input.chord = parse_setting(&name).ok_or_else(|| invalid(&name))?;
+            setting_name(input.chord)
+        }
+        ("chord", _) => setting_name(input.chord),
+        ("key", Some(OscType::String(name))) => {
+            input.key = parse_key(&name).ok_or_else(|| invalid(&name))?;
+            OscType::String(KEY_NAMES[input.key as usize].to_string())
+        }
+        ("key", _) => OscType::String(KEY_NAMES[input.key as usize].to_string()),
+        ("octave", argument) => {
+            if let Some(octave) = argument.as_ref().and_then(osc::as_f32) {
+                input.octave = (octave.round() as i32).clamp(-4, 4);
+            }
+            OscType::Int(input.octave)
+        }
+        (other, _) => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown note setting '{}'.", other),
+            ))
+        }
+    };
+
+    Ok(vec![build_return_message(labeled, addr, reply)])
+}
+
+/// Releases every note Arcflash started and reports how many notes were released.
+pub(super) fn panic(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    lock(&config)?.held.clear();
+    let released = std::mem::take(
+        &mut *sounding()
+            .lock()
+            .map_err(|_| Error::other("Notes lock was poisoned."))?,
+    );
+    info!("Panic, releasing {} notes.", released.len());
+
+    let mut messages: Vec<LabeledMessage> = released
+        .keys()
+        .map(|note| {
+            LabeledMessage::new(
+                labeled.controller(),
+                labeled.instrument(),
+                note_message(*note, 0),
+            )
+        })
+        .collect();
+    messages.push(build_return_message(
+        labeled,
+        String::from("/sys/panic"),
+        OscType::Int(released.len() as i32),
+    ));
+    Ok(messages)
+}
+
+/// Starts a thread that releases every note Arcflash started, from the controller or the
+/// sequencer, once the controller has been silent for too long, as that usually means it
+/// lost its connection.
+pub(crate) fn start_watchdog(config: Arc<Config>) {
+    let release_after_silence = notes_config(&config).release_after_silence;
+    if release_after_silence <= 0.0 {
+        return;
+    }
+    let silence = Duration::from_secs_f32(release_after_silence);
+    let instrument = Arc::new(config.instrument.clone());
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let Ok(mut input) = note_input(&config).lock() else {
+            warn!("Notes lock was poisoned, watchdog stopped.");
+            return;
+        };
+        if input.released || input.last_seen.elapsed() < silence {
+            continue;
+        }
+        input.released = true;
+        input.held.clear();
+        drop(input);
+        let Ok(mut sounding) = sounding().lock() else {
+            warn!("Notes lock was poisoned, watchdog stopped.");
+            return;
+        };
+        let released = std::mem::take(&mut *sounding);
+        drop(sounding);
+        if released.is_empty() {
+            continue;
+        }
+
+        info!(
+            "Controller was silent for {:?}, releasing {} notes.",
+            silence,
+            released.len()
+        );
+        for note in released.into_keys() {
+            if let Err(e) = send_message(note_message(note, 0), instrument.clone()) {
+                warn!("Failed to release note {}: {}", note, e);
+            }
+        }
+    });
+}
+
+// ********
+// Helpers
+// ********
+
+impl NoteInput {
+    /// The notes a controller note plays: quantized to the scale, shifted by the octave
+    /// setting and expanded to the chord.
+    fn notes_for(&self, note: f32) -> Vec<u8> {
+        let intervals = scale_intervals(self.scale);
+        let root = quantize(note.round() as i32, self.key as i32, intervals) + self.octave * 12;
+        let offsets: Vec<i32> = match self.chord {
+            Chord::Off => vec![0],
+            Chord::Power => vec![0, 7],
+            Chord::Octave => vec![0, 12],
+            // Without a scale to stack thirds in, chords are major.
+            Chord::Triad if self.scale == Scale::Chromatic => vec![0, 4, 7],
+            Chord::Seventh if self.scale == Scale::Chromatic => vec![0, 4, 7, 10],
+            Chord::Triad => stacked_thirds(root, self.key as i32, intervals, 3),
+            Chord::Seventh => stacked_thirds(root, self.key as i32, intervals, 4),
+        };
+        offsets
+            .into_iter()
+            .map(|offset| root + offset)
+            .filter(|note| (0..=127).contains(note))
+            .map(|note| note as u8)
+            .collect()
+    }
+}
+
+/// The `[notes]` section, or the defaults for the settings when there is none.
+fn notes_config(config: &Config) -> &NotesConfig {
+    static DEFAULT: OnceLock<NotesConfig> = OnceLock::new();
+    config
+        .notes
+        .as_ref()
+        .unwrap_or_else(|| DEFAULT.get_or_init(NotesConfig::default))
+}
+
+fn lock(config: &Config) -> io::Result<MutexGuard<'static, NoteInput>> {
+    note_input(config)
+        .lock()
+        .map_err(|_| Error::other("Notes lock was poisoned."))
+}
+
+fn scale_intervals(scale: Scale) -> &'static [i32] {
+    match scale {
+        Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+        Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+        Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+        Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+        Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+        Scale::Pentatonic => &[0, 2, 4, 7, 9],
+        Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+        Scale::Blues => &[0, 3, 5, 6, 7, 10],
+    }
+}
+
+/// Moves a note to the nearest note of the scale in the key, the lower one on a tie.
+fn quantize(note: i32, key: i32, intervals: &[i32]) -> i32 {
+    let degree = (note - key).rem_euclid(12);
+    let nearest = intervals
+        .iter()
+        .copied()
+        .chain([12])
+        .min_by_key(|interval| (interval - degree).abs())
+        .unwrap_or(degree);
+    note - degree + nearest
+}
+
+/// Offsets from a root in the scale to every other scale note above it.
+fn stacked_thirds(root: i32, key: i32, intervals: &[i32], count: usize) -> Vec<i32> {
+    let degree = (root - key).rem_euclid(12);
+    let index = intervals.iter().position(|i| *i == degree).unwrap_or(0);
+    (0..count)
+        .map(|n| {
+            let step = index + n * 2;
+            let octave = (step / intervals.len()) as i32;
+            intervals[step % intervals.len()] + octave * 12 - intervals[index]
+        })
+        .collect()
+}
+
+/// Controller velocities are 0 to 1, or 0 to 127 for MIDI-style controls.
+fn shape_velocity(velocity: f32, curve: Curve) -> u8 {
+    let velocity = match velocity > 1.0 {
+        true => velocity / 127.0,
+        false => velocity,
+    }
+    .clamp(0.0, 1.0);
+    let shaped = match curve {
+        Curve::Linear => velocity,
+        Curve::Exponential => velocity * velocity,
+        Curve::Logarithmic => velocity.sqrt(),
+    };
+    (1.0 + shaped * 126.0).round() as u8
+}
+
+/// Accepts key names like C, F# and Bb.
+fn parse_key(name: &str) -> Option<u8> {
+    let mut chars = name.trim().chars();
+    let base: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let shift: i32 = match chars.as_str() {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return None,
+    };
+    Some((base + shift).rem_euclid(12) as u8)
+}
+
+/// Scales and chords are named the same way as in the config file.
+fn parse_setting<T: serde::de::DeserializeOwned>(name: &str) -> Option<T> {
+    toml::Value::String(name.to_lowercase()).try_into().ok()
+}
+
+fn setting_name<T: serde::Serialize>(setting: T) -> OscType {
+    match toml::Value::try_from(setting) {
+        Ok(toml::Value::String(name)) => OscType::String(name),
+        _ => OscType::Nil,
+    }
+}
+
+/// Surge plays a note on /mnote and releases it when the velocity is 0.
+pub(crate) fn note_message(note: u8, velocity: u8) -> osc::Message {
+    osc::msg(
+        "/mnote",
+        vec![OscType::Float(note as f32), OscType::Float(velocity as f32)],
+    )
+}
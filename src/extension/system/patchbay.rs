@@ -1,4 +1,7 @@
-use crate::{config::Config, labeler::LabeledMessage};
+use crate::{
+    config::{data_dir, exchange_file, Config},
+    labeler::LabeledMessage,
+};
 use log::{debug, warn};
 use rosc::OscType;
 use std::{
@@ -7,7 +10,10 @@ use std::{
     sync::Arc,
 };
 
-use super::build_return_message;
+use super::{build_return_message, tuning};
+
+mod archive;
+pub(crate) use archive::{export_archive, import_archive, ImportMode};
 
 /// Checks if the patchbay is occupied and returns bool to sender
 pub(super) fn check_patchbay(
@@ -16,10 +22,7 @@ pub(super) fn check_patchbay(
 ) -> Result<LabeledMessage, io::Error> {
     let requested_patchbay = get_patchbay(&labeled)?;
     let patchbay_path = guarantee_patch_path(config, &requested_patchbay)?;
-    let return_bool = match retrieve_patch_filename_from_bay(&patchbay_path) {
-        Ok(_) => true,
-        Err(_) => false,
-    };
+    let return_bool = retrieve_patch_filename_from_bay(&patchbay_path).is_ok();
     debug!(
         "Patchbay {} check in {:?} returned {}",
         requested_patchbay, patchbay_path, return_bool
@@ -33,36 +36,34 @@ pub(super) fn check_patchbay(
     Ok(result)
 }
 
-/// Loads a patch from the given patchbay.
+/// Loads a patch from the given patchbay, along with the tuning saved with it.
 /// Uses the first .fxp file it can find!
 pub(super) fn load_patch(
     config: Arc<Config>,
     labeled: LabeledMessage,
-) -> Result<LabeledMessage, io::Error> {
+) -> Result<Vec<LabeledMessage>, io::Error> {
     let patchbay = get_patchbay(&labeled)?;
     debug!("Starting load process for patchbay {}", patchbay);
-    let patch_path = guarantee_patch_path(config, &patchbay)?;
+    let patch_path = guarantee_patch_path(config.clone(), &patchbay)?;
 
     let found_patch_name = retrieve_patch_filename_from_bay(&patch_path)?;
 
     // Now we message Surge to save the current patch to this path.
-    let path_with_filename = format!(
-        "{}{}",
-        patch_path.to_string_lossy().to_string(),
-        found_patch_name
-    );
+    let path_with_filename = format!("{}{}", patch_path.to_string_lossy(), found_patch_name);
     let message = rosc::OscMessage {
         addr: String::from("/patch/load"),
         args: vec![OscType::String(path_with_filename)],
     };
-    Ok(LabeledMessage {
+    let tuning_messages = tuning::restore_for_bay(&config, &patch_path, &labeled)?;
+    let load_message = LabeledMessage {
         message,
         peer_recv: labeled.peer_recv,
         peer_send: labeled.peer_send,
-    })
+    };
+    Ok([load_message].into_iter().chain(tuning_messages).collect())
 }
 
-/// Saves a patch in the given patchbay
+/// Saves a patch and the current tuning in the given patchbay
 /// Removes all existing .fxp files from patchbay dir!
 pub(super) fn save_patch(
     config: Arc<Config>,
@@ -75,13 +76,10 @@ pub(super) fn save_patch(
 
     // Clear out the patchbay
     clear_patchbay(&patch_path)?;
+    tuning::save_for_bay(&patch_path)?;
 
     // Now we message Surge to save the current patch to this path.
-    let path_with_filename = format!(
-        "{}{}",
-        patch_path.to_string_lossy().to_string(),
-        current_patch_name
-    );
+    let path_with_filename = format!("{}{}", patch_path.to_string_lossy(), current_patch_name);
     debug!("Asking instrument to save patch to {}", path_with_filename);
 
     let message = rosc::OscMessage {
@@ -95,20 +93,66 @@ pub(super) fn save_patch(
     })
 }
 
+/// Packs all patchbays into the archive file given as first argument.
+pub(super) fn export_patchbays(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<LabeledMessage, io::Error> {
+    let exported =
+        get_archive_path(&config, &labeled).and_then(|file| export_archive(&config, &file));
+    let content = match exported {
+        Ok(count) => OscType::String(format!("Exported {} patchbays.", count)),
+        Err(e) => {
+            warn!("Failed to export patchbays: {}", e);
+            OscType::String(format!("Error: {}", e))
+        }
+    };
+    Ok(build_return_message(
+        labeled,
+        String::from("/sys/patchbay/export"),
+        content,
+    ))
+}
+
+/// Unpacks the archive file given as first argument into the patch cache.
+/// The optional second argument is the import mode, merge (default) or replace.
+pub(super) fn import_patchbays(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<LabeledMessage, io::Error> {
+    let mode = match labeled
+        .message
+        .args
+        .get(1)
+        .and_then(|arg| arg.clone().string())
+    {
+        Some(mode) => mode.parse::<ImportMode>(),
+        None => Ok(ImportMode::default()),
+    };
+    let imported = mode.and_then(|mode| {
+        get_archive_path(&config, &labeled).and_then(|file| import_archive(&config, &file, mode))
+    });
+    let content = match imported {
+        Ok(count) => OscType::String(format!("Imported {} patchbays.", count)),
+        Err(e) => {
+            warn!("Failed to import patchbays: {}", e);
+            OscType::String(format!("Error: {}", e))
+        }
+    };
+    Ok(build_return_message(
+        labeled,
+        String::from("/sys/patchbay/import"),
+        content,
+    ))
+}
+
 // ********
 // Helpers
 // ********
 
 fn guarantee_patch_path(config: Arc<Config>, patchbay: &String) -> io::Result<PathBuf> {
     let patch_path: PathBuf = {
-        let mut path = dirs::config_local_dir().ok_or_else(|| {
-            io::Error::new(io::ErrorKind::NotFound, "Could not find home directory")
-        })?;
-        let subdir = match &config.options.patch_cache_path.strip_prefix('/') {
-            Some(dir) => dir.to_string(),
-            None => config.options.patch_cache_path.to_owned(),
-        };
-        path.push(subdir);
+        let mut path = patch_cache_dir(&config)?;
         path.push(format!("{}/", patchbay));
         path
     };
@@ -117,10 +161,7 @@ fn guarantee_patch_path(config: Arc<Config>, patchbay: &String) -> io::Result<Pa
     if !patch_path.exists() {
         if let Err(e) = std::fs::create_dir_all(&patch_path) {
             warn!("Failed to create directory {:?}: {}", patch_path, e);
-            return Err(Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to create directory: {}", e),
-            ));
+            return Err(Error::other(format!("Failed to create directory: {}", e)));
         }
         debug!("Created patchbay directory for {:?}", patch_path);
     }
@@ -128,11 +169,16 @@ fn guarantee_patch_path(config: Arc<Config>, patchbay: &String) -> io::Result<Pa
     Ok(patch_path)
 }
 
+/// The directory all patchbays live in.
+fn patch_cache_dir(config: &Config) -> io::Result<PathBuf> {
+    data_dir(&config.options, &config.options.patch_cache_path)
+}
+
 fn get_patchbay(labeled: &LabeledMessage) -> io::Result<String> {
     labeled
         .message
         .args
-        .get(0)
+        .first()
         .and_then(|f| f.to_owned().string())
         .ok_or_else(|| {
             Error::new(
@@ -142,6 +188,21 @@ fn get_patchbay(labeled: &LabeledMessage) -> io::Result<String> {
         })
 }
 
+fn get_archive_path(config: &Config, labeled: &LabeledMessage) -> io::Result<PathBuf> {
+    let name = labeled
+        .message
+        .args
+        .first()
+        .and_then(|arg| arg.clone().string())
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                "First argument not found or not a string.",
+            )
+        })?;
+    exchange_file(&config.options, &name)
+}
+
 fn get_patchname(labeled: &LabeledMessage) -> io::Result<String> {
     let pathstr = labeled
         .message
@@ -168,7 +229,7 @@ fn get_patchname(labeled: &LabeledMessage) -> io::Result<String> {
 fn clear_patchbay(path: &PathBuf) -> Result<(), io::Error> {
     // Delete all sfx files in this cache dir. When we load from this patchbay we take
     // the first and only patch we find in there.
-    let old_patch_files = std::fs::read_dir(&path)?.filter_map(|entry| {
+    let old_patch_files = std::fs::read_dir(path)?.filter_map(|entry| {
         let entry = entry.ok()?;
         let path = entry.path();
         if path.extension()? == "fxp" {
@@ -180,10 +241,7 @@ fn clear_patchbay(path: &PathBuf) -> Result<(), io::Error> {
     for patch_file in old_patch_files {
         if let Err(e) = std::fs::remove_file(&patch_file) {
             warn!("Failed to delete file {:?}: {}", patch_file, e);
-            return Err(Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to delete file: {}", e),
-            ));
+            return Err(Error::other(format!("Failed to delete file: {}", e)));
         }
     }
 
@@ -191,7 +249,7 @@ fn clear_patchbay(path: &PathBuf) -> Result<(), io::Error> {
 }
 
 fn retrieve_patch_filename_from_bay(path: &PathBuf) -> Result<String, io::Error> {
-    let filename = std::fs::read_dir(&path)?
+    let filename = std::fs::read_dir(path)?
         .filter_map(Result::ok)
         .find_map(|entry| {
             let path = entry.path();
@@ -0,0 +1,154 @@
+use crate::config::Config;
+use log::{debug, info};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, Error, Seek},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use tar::{Archive, Builder, EntryType};
+
+use super::{clear_patchbay, patch_cache_dir};
+
+/// What to do with the patchbays already in the cache when importing an archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum ImportMode {
+    /// Bays in the archive overwrite bays with the same name, all others are kept.
+    #[default]
+    Merge,
+    /// All existing bays are removed before the archive is unpacked.
+    Replace,
+}
+
+impl FromStr for ImportMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            other => Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown import mode '{}', use merge or replace.", other),
+            )),
+        }
+    }
+}
+
+/// Packs every patchbay in the cache, including all files in it, into a tar archive.
+/// Returns the number of exported patchbays.
+pub(crate) fn export_archive(config: &Config, file: &Path) -> io::Result<usize> {
+    let cache_dir = patch_cache_dir(config)?;
+    let mut bays: Vec<PathBuf> = std::fs::read_dir(&cache_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    bays.sort();
+
+    let mut builder = Builder::new(File::create(file)?);
+    for bay in &bays {
+        let Some(name) = bay.file_name() else {
+            continue;
+        };
+        debug!("Adding patchbay {:?} to archive", name);
+        builder.append_dir_all(name, bay)?;
+    }
+    builder.into_inner()?.sync_all()?;
+
+    info!("Exported {} patchbays to {:?}", bays.len(), file);
+    Ok(bays.len())
+}
+
+/// Unpacks a patchbay archive into the cache. The whole archive is checked before anything
+/// is written, archives with entries that would end up outside the cache are refused.
+/// Returns the number of imported patchbays.
+pub(crate) fn import_archive(config: &Config, file: &Path, mode: ImportMode) -> io::Result<usize> {
+    // Checked and unpacked from the same handle, so the file can't be swapped in between.
+    let mut archive_file = File::open(file)?;
+    let bays = validate_archive(&archive_file)?;
+    archive_file.rewind()?;
+    let cache_dir = patch_cache_dir(config)?;
+    std::fs::create_dir_all(&cache_dir)?;
+
+    match mode {
+        ImportMode::Replace => {
+            for entry in std::fs::read_dir(&cache_dir)?.filter_map(Result::ok) {
+                if entry.path().is_dir() {
+                    debug!("Removing patchbay {:?}", entry.path());
+                    std::fs::remove_dir_all(entry.path())?;
+                }
+            }
+        }
+        // A patchbay holds a single patch, so clear the bays we are about to overwrite.
+        ImportMode::Merge => {
+            for bay in &bays {
+                let bay_path = cache_dir.join(bay);
+                if bay_path.is_dir() {
+                    clear_patchbay(&bay_path)?;
+                }
+            }
+        }
+    }
+
+    Archive::new(archive_file).unpack(&cache_dir)?;
+
+    info!(
+        "Imported {} patchbays from {:?} ({:?})",
+        bays.len(),
+        file,
+        mode
+    );
+    Ok(bays.len())
+}
+
+// ********
+// Helpers
+// ********
+
+/// Returns the names of the patchbays in the archive or an error if any entry is not a plain
+/// file or directory, or has a path that could escape the cache directory.
+fn validate_archive(file: &File) -> io::Result<BTreeSet<String>> {
+    let mut archive = Archive::new(file);
+    let mut bays = BTreeSet::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => {}
+            other => {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Refusing archive: {:?} is a {:?} entry.", path, other),
+                ))
+            }
+        }
+
+        if !is_contained(&path) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Refusing archive: {:?} points outside the cache.", path),
+            ));
+        }
+
+        // Files at the top level of the archive are not part of a patchbay.
+        let mut components = path.components().filter(|c| c != &Component::CurDir);
+        if let Some(Component::Normal(bay)) = components.next() {
+            if entry.header().entry_type().is_dir() || components.next().is_some() {
+                bays.insert(bay.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    Ok(bays)
+}
+
+/// Only relative paths without parent references stay inside the directory they are
+/// unpacked in.
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
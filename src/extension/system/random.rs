@@ -0,0 +1,132 @@
+use crate::{
+    config::Config,
+    extension::{param_store, type_lookup},
+    labeler::LabeledMessage,
+    osc,
+};
+use log::debug;
+use rand::Rng;
+use rosc::OscType;
+use std::{
+    io::{self, Error},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use super::build_return_message;
+
+/// Parameter values from before the last randomization, so it can be reverted.
+fn previous_values() -> &'static Mutex<Vec<(String, OscType)>> {
+    static PREVIOUS: OnceLock<Mutex<Vec<(String, OscType)>>> = OnceLock::new();
+    PREVIOUS.get_or_init(|| Mutex::new(vec![]))
+}
+
+/// Randomizes the parameters in the group named by the first argument and sends the new
+/// values to the instrument and the controller. Only parameters whose address is known,
+/// e.g. after /sys/snapshot/query, can be matched.
+pub(super) fn randomize(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let name = labeled
+        .message
+        .args
+        .first()
+        .and_then(|arg| arg.clone().string())
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                "First argument not found or not a string.",
+            )
+        })?;
+    let group = config
+        .random_groups
+        .iter()
+        .find(|group| group.name == name)
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown random group '{}'.", name),
+            )
+        })?;
+
+    let known_params = param_store::all();
+    let mut rng = rand::thread_rng();
+    let mut previous = vec![];
+    let mut messages = vec![];
+    for param in &group.params {
+        let (min, max) = (param.min.min(param.max), param.min.max(param.max));
+
+        for (addr, value) in known_params
+            .iter()
+            .filter(|(a, _)| param.pattern.matches(a))
+        {
+            // Only continuous parameters make sense to randomize.
+            let OscType::Float(current) = value else {
+                continue;
+            };
+            let target = rng.gen_range(min..=max);
+            let new_value = current + (target - current) * param.amount.clamp(0.0, 1.0);
+
+            previous.push((addr.clone(), value.clone()));
+            messages.extend(send_to_both(&labeled, addr, OscType::Float(new_value))?);
+        }
+    }
+    debug!(
+        "Random group {} changed {} parameters",
+        name,
+        previous.len()
+    );
+
+    // Randomizing nothing keeps the values of the last randomization to revert to.
+    let count = previous.len();
+    if count > 0 {
+        *previous_values().lock().map_err(|_| poisoned())? = previous;
+    }
+    messages.push(build_return_message(
+        labeled,
+        String::from("/sys/random"),
+        OscType::String(format!("Randomized {} parameters.", count)),
+    ));
+    Ok(messages)
+}
+
+/// Sends the values from before the last randomization.
+pub(super) fn revert(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let previous = std::mem::take(&mut *previous_values().lock().map_err(|_| poisoned())?);
+    let mut messages = vec![];
+    for (addr, value) in &previous {
+        messages.extend(send_to_both(&labeled, addr, value.clone())?);
+    }
+    messages.push(build_return_message(
+        labeled,
+        String::from("/sys/random/revert"),
+        OscType::String(format!("Reverted {} parameters.", previous.len())),
+    ));
+    Ok(messages)
+}
+
+// ********
+// Helpers
+// ********
+
+/// Sends the value to the instrument and, translated where needed, to the controller.
+fn send_to_both(
+    labeled: &LabeledMessage,
+    addr: &str,
+    value: OscType,
+) -> io::Result<[LabeledMessage; 2]> {
+    let message = osc::msg(addr, vec![value]);
+    param_store::record(&message);
+    Ok([
+        LabeledMessage::new(labeled.controller(), labeled.instrument(), message.clone()),
+        type_lookup(LabeledMessage::new(
+            labeled.instrument(),
+            labeled.controller(),
+            message,
+        ))?,
+    ])
+}
+
+fn poisoned() -> Error {
+    Error::other("Randomizer lock was poisoned.")
+}
@@ -0,0 +1,308 @@
+use crate::{
+    config::{Config, SequencerConfig},
+    labeler::LabeledMessage,
+    osc,
+    peer::Peer,
+    sender::send_message,
+};
+use log::{debug, warn};
+use rand::Rng;
+use rosc::OscType;
+use std::{
+    io::{self, Error},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    build_return_message,
+    notes::{self, note_message},
+};
+
+const MAX_STEPS: usize = 32;
+/// Faster than this, steps get shorter than the playback thread can keep up with.
+const MAX_TEMPO: f32 = 999.0;
+
+/// A single step. The gate is the part of the step the note is held, the probability the
+/// chance the note is played at all.
+#[derive(Clone, Copy, Debug)]
+struct Step {
+    on: bool,
+    note: u8,
+    velocity: u8,
+    gate: f32,
+    probability: f32,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            on: false,
+            note: 60,
+            velocity: 100,
+            gate: 0.5,
+            probability: 1.0,
+        }
+    }
+}
+
+struct Sequencer {
+    steps: [Step; MAX_STEPS],
+    length: usize,
+    tempo: f32,
+    steps_per_beat: u32,
+    /// Set to stop the playback thread.
+    playing: Option<Arc<AtomicBool>>,
+}
+
+impl Sequencer {
+    fn new(config: &SequencerConfig) -> Self {
+        Self {
+            steps: [Step::default(); MAX_STEPS],
+            length: valid_length(config.steps),
+            tempo: config.tempo.clamp(1.0, MAX_TEMPO),
+            steps_per_beat: config.steps_per_beat.max(1),
+            playing: None,
+        }
+    }
+
+    fn step_duration(&self) -> Duration {
+        Duration::from_secs_f32(60.0 / self.tempo / self.steps_per_beat as f32)
+    }
+
+    fn stop_playing(&mut self) {
+        if let Some(stop) = self.playing.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn sequencer(config: &Config) -> &'static Mutex<Sequencer> {
+    static SEQUENCER: OnceLock<Mutex<Sequencer>> = OnceLock::new();
+    SEQUENCER.get_or_init(|| Mutex::new(Sequencer::new(&config.sequencer)))
+}
+
+/// Handles /sys/seq/play, /stop, /clear, /tempo, /length and
+/// /sys/seq/step/<n>/on, /note, /velocity, /gate and /probability. Without an argument
+/// the current setting is reported.
+pub(super) fn seq_handler(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let addr = labeled.message.addr.clone();
+    let command = addr.split("/sys/seq/").nth(1).unwrap_or_default();
+    // NaN or infinity would stall or kill the playback thread.
+    let argument = labeled
+        .message
+        .args
+        .first()
+        .and_then(osc::as_f32)
+        .filter(|value| value.is_finite());
+
+    let mut seq = lock(&config)?;
+    let reply = match command {
+        "play" => {
+            if seq.playing.is_none() {
+                seq.playing = Some(start_playback(
+                    config.clone(),
+                    labeled.controller(),
+                    labeled.instrument(),
+                ));
+            }
+            ("/sys/seq/playing".to_string(), OscType::Bool(true))
+        }
+        "stop" => {
+            seq.stop_playing();
+            ("/sys/seq/playing".to_string(), OscType::Bool(false))
+        }
+        "clear" => {
+            seq.steps = [Step::default(); MAX_STEPS];
+            ("/sys/seq/clear".to_string(), OscType::Bool(true))
+        }
+        "tempo" => {
+            if let Some(tempo) = argument {
+                seq.tempo = tempo.clamp(1.0, MAX_TEMPO);
+            }
+            ("/sys/seq/tempo".to_string(), OscType::Float(seq.tempo))
+        }
+        "length" => {
+            if let Some(length) = argument {
+                seq.length = valid_length(length as usize);
+            }
+            (
+                "/sys/seq/length".to_string(),
+                OscType::Int(seq.length as i32),
+            )
+        }
+        step if step.starts_with("step/") => edit_step(&mut seq, step, argument)?,
+        other => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown sequencer command '{}'.", other),
+            ))
+        }
+    };
+    debug!("Sequencer {} is {:?}", reply.0, reply.1);
+
+    Ok(vec![build_return_message(labeled, reply.0, reply.1)])
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock(config: &Config) -> io::Result<MutexGuard<'static, Sequencer>> {
+    sequencer(config)
+        .lock()
+        .map_err(|_| Error::other("Sequencer lock was poisoned."))
+}
+
+/// Only 16 and 32 steps are supported, anything else rounds to the nearest of the two.
+fn valid_length(steps: usize) -> usize {
+    if steps > 24 {
+        32
+    } else {
+        16
+    }
+}
+
+/// Changes a field of the step in `step/<n>/<field>`, steps are numbered from 1.
+fn edit_step(
+    seq: &mut Sequencer,
+    command: &str,
+    argument: Option<f32>,
+) -> io::Result<(String, OscType)> {
+    let mut parts = command.split('/').skip(1);
+    let (Some(number), Some(field)) = (parts.next(), parts.next()) else {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            "Use /sys/seq/step/<n>/on, /note, /velocity, /gate or /probability.",
+        ));
+    };
+    let index = number
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (1..=MAX_STEPS).contains(n))
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Step '{}' is not between 1 and {}.", number, MAX_STEPS),
+            )
+        })?
+        - 1;
+    let step = &mut seq.steps[index];
+
+    let value = match field {
+        "on" => {
+            step.on = match argument {
+                Some(on) => on >= 0.5,
+                None => step.on,
+            };
+            OscType::Bool(step.on)
+        }
+        "note" => {
+            if let Some(note) = argument {
+                step.note = note.round().clamp(0.0, 127.0) as u8;
+            }
+            OscType::Int(step.note as i32)
+        }
+        "velocity" => {
+            if let Some(velocity) = argument {
+                step.velocity = velocity.round().clamp(1.0, 127.0) as u8;
+            }
+            OscType::Int(step.velocity as i32)
+        }
+        "gate" => {
+            if let Some(gate) = argument {
+                step.gate = gate.clamp(0.05, 1.0);
+            }
+            OscType::Float(step.gate)
+        }
+        "probability" => {
+            if let Some(probability) = argument {
+                step.probability = probability.clamp(0.0, 1.0);
+            }
+            OscType::Float(step.probability)
+        }
+        other => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown step field '{}'.", other),
+            ))
+        }
+    };
+    Ok((format!("/sys/seq/step/{}/{}", index + 1, field), value))
+}
+
+/// Plays the steps until the returned flag is set. The playhead position is sent to the
+/// controller on every step. Tempo, length and step changes apply from the next step.
+fn start_playback(
+    config: Arc<Config>,
+    controller: Arc<Peer>,
+    instrument: Arc<Peer>,
+) -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_thread = stop.clone();
+
+    std::thread::spawn(move || {
+        let mut rng = rand::thread_rng();
+        let mut position = 0;
+        let mut next_step = Instant::now();
+        loop {
+            if stop_thread.load(Ordering::Relaxed) {
+                debug!("Sequencer stopped playing");
+                return;
+            }
+            let Ok(seq) = sequencer(&config).lock() else {
+                warn!("Sequencer lock was poisoned, playback stopped.");
+                return;
+            };
+            position %= seq.length;
+            let step = seq.steps[position];
+            let duration = seq.step_duration();
+            drop(seq);
+
+            send(
+                osc::msg("/sys/seq/playhead", vec![OscType::Int(position as i32 + 1)]),
+                &controller,
+            );
+            let play = step.on && rng.gen::<f32>() < step.probability;
+            if play {
+                send_note(step.note, step.velocity, &instrument);
+            }
+
+            // A note always ends within its own step, even if playback is stopped.
+            let step_start = next_step;
+            next_step += duration;
+            if play {
+                sleep_until(step_start + duration.mul_f32(step.gate));
+                send_note(step.note, 0, &instrument);
+            }
+            sleep_until(next_step);
+            position += 1;
+        }
+    });
+    stop
+}
+
+fn send(message: osc::Message, peer: &Arc<Peer>) {
+    if let Err(e) = send_message(message, peer.clone()) {
+        warn!("Sequencer failed to send: {}", e);
+    }
+}
+
+/// Notes go through the note tracker so /sys/panic can release them.
+fn send_note(note: u8, velocity: u8, instrument: &Arc<Peer>) {
+    let message = note_message(note, velocity);
+    notes::track(&message);
+    send(message, instrument);
+}
+
+fn sleep_until(deadline: Instant) {
+    if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+        std::thread::sleep(wait);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{config::Config, labeler::LabeledMessage, osc};
+use log::{debug, warn};
+use rosc::OscType;
+use serde::Deserialize;
+use std::{
+    io::{self, Error},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use super::{patchbay, snapshot};
+
+/// An ordered list of songs, read from the setlist file in the config.
+#[derive(Deserialize, Debug)]
+struct Setlist {
+    #[serde(rename = "song")]
+    songs: Vec<Song>,
+}
+
+/// A song recalls either a patchbay or a parameter snapshot.
+#[derive(Deserialize, Debug)]
+struct Song {
+    title: String,
+    patchbay: Option<String>,
+    snapshot: Option<String>,
+}
+
+/// Index of the current song in the setlist, if we started playing it.
+fn position() -> &'static Mutex<Option<usize>> {
+    static POSITION: OnceLock<Mutex<Option<usize>>> = OnceLock::new();
+    POSITION.get_or_init(|| Mutex::new(None))
+}
+
+/// Moves to the next song and loads it.
+pub(super) fn next_song(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    goto_song(config, labeled, |current| current.map_or(0, |i| i + 1))
+}
+
+/// Moves to the previous song and loads it.
+pub(super) fn previous_song(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    goto_song(config, labeled, |current| {
+        current.map_or(0, |i| i.saturating_sub(1))
+    })
+}
+
+/// Loads the song with the number given as first argument. Songs are numbered from 1.
+pub(super) fn goto_requested_song(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let number = labeled
+        .message
+        .args
+        .first()
+        .and_then(osc::as_i64)
+        .unwrap_or_default();
+    if number < 1 {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            "First argument must be a song number starting at 1.",
+        ));
+    }
+    goto_song(config, labeled, |_| number as usize - 1)
+}
+
+// ********
+// Helpers
+// ********
+
+/// Loads the song at the index `target` picks from the current one and tells the controller
+/// where we are. Moving past either end of the setlist keeps us on the first or last song.
+fn goto_song(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+    target: impl FnOnce(Option<usize>) -> usize,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let setlist = read_setlist(&config)?;
+    if setlist.songs.is_empty() {
+        return Err(Error::new(io::ErrorKind::NotFound, "Setlist is empty."));
+    }
+    // Move on even if the song fails to load, so a broken song doesn't block the setlist.
+    // Reading and moving the position under one lock keeps two presses from landing on
+    // the same song.
+    let index = {
+        let mut position = position().lock().map_err(|_| poisoned())?;
+        let index = target(*position).min(setlist.songs.len() - 1);
+        *position = Some(index);
+        index
+    };
+    let song = &setlist.songs[index];
+    debug!("Setlist moving to song {}: {}", index + 1, song.title);
+
+    let controller = labeled.controller();
+    let mut messages = match load_song(config, &labeled, song) {
+        Ok(messages) => messages,
+        Err(e) => {
+            warn!("Failed to load song '{}': {}", song.title, e);
+            vec![LabeledMessage::new(
+                controller.clone(),
+                controller.clone(),
+                osc::msg("/sys/debug", vec![OscType::String(format!("Error: {}", e))]),
+            )]
+        }
+    };
+
+    messages.push(LabeledMessage::new(
+        controller.clone(),
+        controller.clone(),
+        osc::msg(
+            "/sys/setlist/title",
+            vec![OscType::String(song.title.clone())],
+        ),
+    ));
+    messages.push(LabeledMessage::new(
+        controller.clone(),
+        controller,
+        osc::msg(
+            "/sys/setlist/position",
+            vec![
+                OscType::Int(index as i32 + 1),
+                OscType::Int(setlist.songs.len() as i32),
+            ],
+        ),
+    ));
+    Ok(messages)
+}
+
+/// Runs the patchbay or snapshot command for the song as if the controller sent it.
+fn load_song(
+    config: Arc<Config>,
+    labeled: &LabeledMessage,
+    song: &Song,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut request = labeled.clone();
+    request.peer_send = labeled.instrument();
+    request.peer_recv = labeled.controller();
+    match (&song.patchbay, &song.snapshot) {
+        (Some(bay), _) => {
+            request.message = osc::msg("/sys/patchbay/load", vec![OscType::String(bay.clone())]);
+            patchbay::load_patch(config, request)
+        }
+        (None, Some(name)) => {
+            request.message = osc::msg("/sys/snapshot/load", vec![OscType::String(name.clone())]);
+            snapshot::load_snapshot(config, request)
+        }
+        (None, None) => Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Song '{}' has no patchbay or snapshot.", song.title),
+        )),
+    }
+}
+
+/// The setlist is read on every command so it can be edited between songs.
+fn read_setlist(config: &Config) -> io::Result<Setlist> {
+    let path = config
+        .options
+        .setlist_file
+        .as_ref()
+        .ok_or_else(|| Error::new(io::ErrorKind::NotFound, "No setlist file configured."))?;
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str::<Setlist>(&contents).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to read setlist {:?}: {}", path, e),
+        )
+    })
+}
+
+fn poisoned() -> Error {
+    Error::other("Setlist position lock was poisoned.")
+}
@@ -0,0 +1,189 @@
+use crate::{
+    config::{data_dir, Config},
+    extension::param_store,
+    labeler::LabeledMessage,
+    osc,
+};
+use log::{debug, warn};
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{self, Error},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use super::build_return_message;
+
+/// A parameter snapshot as stored on disk. Unlike patchbays, snapshots don't need the
+/// instrument to read or write files, so they work when the instrument runs elsewhere.
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct Snapshot {
+    params: BTreeMap<String, ParamValue>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum ParamValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+}
+
+impl ParamValue {
+    fn from_osc(value: &OscType) -> Option<Self> {
+        match value {
+            OscType::Bool(b) => Some(ParamValue::Bool(*b)),
+            OscType::Int(i) => Some(ParamValue::Int(*i as i64)),
+            OscType::Long(l) => Some(ParamValue::Int(*l)),
+            // Go through the shortest representation so 0.7 is stored as 0.7.
+            OscType::Float(f) => f.to_string().parse().ok().map(ParamValue::Float),
+            OscType::Double(d) => Some(ParamValue::Float(*d)),
+            _ => None,
+        }
+    }
+
+    fn to_osc(&self) -> OscType {
+        match self {
+            ParamValue::Bool(b) => OscType::Bool(*b),
+            ParamValue::Int(i) => OscType::Int(*i as i32),
+            ParamValue::Float(f) => OscType::Float(*f as f32),
+        }
+    }
+}
+
+/// Writes all parameter values Arcflash knows about to the snapshot given as first argument.
+/// Send /sys/snapshot/query first to make sure all parameters are known.
+pub(super) fn save_snapshot(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<LabeledMessage, io::Error> {
+    let name = get_snapshot_name(&labeled)?;
+    let snapshot = Snapshot {
+        params: param_store::all()
+            .iter()
+            .filter_map(|(addr, value)| Some((addr.clone(), ParamValue::from_osc(value)?)))
+            .collect(),
+    };
+    let path = guarantee_snapshot_path(&config, &name)?;
+    let contents = toml::to_string(&snapshot).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to serialize snapshot: {}", e),
+        )
+    })?;
+    std::fs::write(&path, contents)?;
+    debug!(
+        "Saved {} parameters to snapshot {:?}",
+        snapshot.params.len(),
+        path
+    );
+
+    Ok(build_return_message(
+        labeled,
+        String::from("/sys/snapshot/save"),
+        OscType::String(format!("Saved {} parameters.", snapshot.params.len())),
+    ))
+}
+
+/// Sends every parameter in the snapshot given as first argument to the instrument.
+pub(super) fn load_snapshot(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let name = get_snapshot_name(&labeled)?;
+    let path = guarantee_snapshot_path(&config, &name)?;
+    let contents = std::fs::read_to_string(&path)?;
+    let snapshot = toml::from_str::<Snapshot>(&contents).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to read snapshot {:?}: {}", path, e),
+        )
+    })?;
+    debug!(
+        "Recalling {} parameters from snapshot {:?}",
+        snapshot.params.len(),
+        path
+    );
+
+    let instrument = labeled.instrument();
+    let mut messages: Vec<LabeledMessage> = snapshot
+        .params
+        .iter()
+        .map(|(addr, value)| {
+            LabeledMessage::new(
+                labeled.peer_recv.clone(),
+                instrument.clone(),
+                osc::msg(addr.as_str(), vec![value.to_osc()]),
+            )
+        })
+        .collect();
+    messages.push(build_return_message(
+        labeled,
+        String::from("/sys/snapshot/load"),
+        OscType::String(format!("Loaded {} parameters.", snapshot.params.len())),
+    ));
+
+    Ok(messages)
+}
+
+/// Checks if the snapshot exists and returns bool to sender
+pub(super) fn check_snapshot(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<LabeledMessage, io::Error> {
+    let name = get_snapshot_name(&labeled)?;
+    let exists = guarantee_snapshot_path(&config, &name)?.exists();
+    Ok(build_return_message(
+        labeled,
+        format!("/sys/snapshot/check/{}", name),
+        OscType::Bool(exists),
+    ))
+}
+
+/// Asks the instrument for all its parameter values so the snapshot is complete.
+pub(super) fn query_params(labeled: LabeledMessage) -> Result<LabeledMessage, io::Error> {
+    let instrument = labeled.instrument();
+    Ok(LabeledMessage::new(
+        labeled.peer_recv,
+        instrument,
+        osc::msg("/q/all_params", vec![]),
+    ))
+}
+
+// ********
+// Helpers
+// ********
+
+fn guarantee_snapshot_path(config: &Config, name: &str) -> io::Result<PathBuf> {
+    let snapshot_dir = data_dir(&config.options, &config.options.snapshot_path)?;
+    if !snapshot_dir.exists() {
+        if let Err(e) = std::fs::create_dir_all(&snapshot_dir) {
+            warn!("Failed to create directory {:?}: {}", snapshot_dir, e);
+            return Err(Error::other(format!("Failed to create directory: {}", e)));
+        }
+    }
+    Ok(snapshot_dir.join(format!("{}.toml", name)))
+}
+
+fn get_snapshot_name(labeled: &LabeledMessage) -> io::Result<String> {
+    let name = labeled
+        .message
+        .args
+        .first()
+        .and_then(|arg| arg.clone().string())
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                "First argument not found or not a string.",
+            )
+        })?;
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid snapshot name '{}'.", name),
+        ));
+    }
+    Ok(name)
+}
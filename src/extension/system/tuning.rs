@@ -0,0 +1,308 @@
+use crate::{config::Config, labeler::LabeledMessage, osc};
+use log::{debug, info, warn};
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{self, Error},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+};
+
+use super::build_return_message;
+
+/// The file in a patchbay that remembers the tuning saved with it.
+const BAY_TUNING_FILE: &str = "tuning.toml";
+
+/// A tuning is a scale, a keyboard mapping or both, when they share a file name.
+#[derive(Debug, Clone)]
+struct Tuning {
+    name: String,
+    description: String,
+    scl: Option<PathBuf>,
+    kbm: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct Tunings {
+    /// Indexed on first use and kept until arcflash restarts.
+    index: Option<Arc<Vec<Tuning>>>,
+    /// The tuning we last sent to the instrument, `None` for standard tuning.
+    current: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct BayTuning {
+    tuning: Option<String>,
+}
+
+fn tunings() -> &'static Mutex<Tunings> {
+    static TUNINGS: OnceLock<Mutex<Tunings>> = OnceLock::new();
+    TUNINGS.get_or_init(|| Mutex::new(Tunings::default()))
+}
+
+/// Sends the number of tunings and the name and description of each tuning.
+pub(super) fn list(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let index = get_index(&config, &mut *lock()?);
+    let mut messages = vec![build_return_message(
+        labeled.clone(),
+        String::from("/sys/tuning/count"),
+        OscType::Int(index.len() as i32),
+    )];
+    for (i, tuning) in index.iter().enumerate() {
+        let mut message = build_return_message(
+            labeled.clone(),
+            format!("/sys/tuning/item/{}", i + 1),
+            OscType::String(tuning.name.clone()),
+        );
+        message
+            .message
+            .args
+            .push(OscType::String(tuning.description.clone()));
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Loads the tuning after the current one, starting over after the last.
+pub(super) fn next(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut tunings = lock()?;
+    let index = get_index(&config, &mut tunings);
+    let position = tunings
+        .current
+        .as_ref()
+        .and_then(|current| index.iter().position(|t| &t.name == current))
+        .map(|i| (i + 1) % index.len())
+        .unwrap_or(0);
+    let tuning = index
+        .get(position)
+        .ok_or_else(|| Error::new(io::ErrorKind::NotFound, "No tunings found."))?;
+    Ok(load_tuning(&mut tunings, tuning, &labeled))
+}
+
+/// Loads the tuning named, or numbered from 1, by the first argument.
+pub(super) fn load(
+    config: Arc<Config>,
+    labeled: LabeledMessage,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    let mut tunings = lock()?;
+    let index = get_index(&config, &mut tunings);
+    let tuning = match labeled.message.args.first() {
+        Some(OscType::String(name)) => find_tuning(&index, name),
+        Some(number) => osc::as_i64(number)
+            .and_then(|number| index.get(usize::try_from(number).ok()?.checked_sub(1)?)),
+        None => None,
+    }
+    .ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::NotFound,
+            "First argument is not a known tuning name or number.",
+        )
+    })?;
+    Ok(load_tuning(&mut tunings, tuning, &labeled))
+}
+
+/// Puts the instrument back in standard tuning.
+pub(super) fn reset(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    Ok(reset_tuning(&mut *lock()?, &labeled))
+}
+
+/// Remembers the current tuning in a patchbay that is being saved.
+pub(super) fn save_for_bay(patchbay_path: &Path) -> io::Result<()> {
+    let bay_tuning = BayTuning {
+        tuning: lock()?.current.clone(),
+    };
+    let contents = toml::to_string(&bay_tuning).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to serialize tuning: {}", e),
+        )
+    })?;
+    std::fs::write(patchbay_path.join(BAY_TUNING_FILE), contents)
+}
+
+/// Restores the tuning saved with a patchbay that is being loaded. Patchbays saved before
+/// arcflash knew about tunings leave the tuning alone.
+pub(super) fn restore_for_bay(
+    config: &Config,
+    patchbay_path: &Path,
+    labeled: &LabeledMessage,
+) -> io::Result<Vec<LabeledMessage>> {
+    let Ok(contents) = std::fs::read_to_string(patchbay_path.join(BAY_TUNING_FILE)) else {
+        return Ok(vec![]);
+    };
+    let bay_tuning = toml::from_str::<BayTuning>(&contents).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to read patchbay tuning: {}", e),
+        )
+    })?;
+
+    let mut tunings = lock()?;
+    let Some(name) = bay_tuning.tuning else {
+        return Ok(reset_tuning(&mut tunings, labeled));
+    };
+    let index = get_index(config, &mut tunings);
+    match find_tuning(&index, &name) {
+        Some(tuning) => Ok(load_tuning(&mut tunings, tuning, labeled)),
+        None => {
+            warn!("Patchbay tuning '{}' is not in the tuning library.", name);
+            Ok(vec![])
+        }
+    }
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> io::Result<MutexGuard<'static, Tunings>> {
+    tunings()
+        .lock()
+        .map_err(|_| Error::other("Tuning lock was poisoned."))
+}
+
+fn get_index(config: &Config, tunings: &mut Tunings) -> Arc<Vec<Tuning>> {
+    tunings
+        .index
+        .get_or_insert_with(|| Arc::new(index_tunings(&config.tuning.paths)))
+        .clone()
+}
+
+fn find_tuning<'a>(index: &'a [Tuning], name: &str) -> Option<&'a Tuning> {
+    index.iter().find(|t| t.name.eq_ignore_ascii_case(name))
+}
+
+/// Sends the scale and keyboard mapping files to the instrument and reports the tuning
+/// to the controller.
+fn load_tuning(
+    tunings: &mut Tunings,
+    tuning: &Tuning,
+    labeled: &LabeledMessage,
+) -> Vec<LabeledMessage> {
+    debug!("Loading tuning {}", tuning.name);
+    tunings.current = Some(tuning.name.clone());
+    let mut messages: Vec<LabeledMessage> =
+        [("/tuning/scl", &tuning.scl), ("/tuning/kbm", &tuning.kbm)]
+            .into_iter()
+            .filter_map(|(addr, path)| Some(instrument_message(labeled, addr, path.as_ref()?)))
+            .collect();
+    messages.push(current_message(labeled, &tuning.name, &tuning.description));
+    messages
+}
+
+/// An empty path resets the instrument to standard tuning and mapping.
+fn reset_tuning(tunings: &mut Tunings, labeled: &LabeledMessage) -> Vec<LabeledMessage> {
+    debug!("Resetting tuning");
+    tunings.current = None;
+    vec![
+        instrument_message(labeled, "/tuning/scl", Path::new("")),
+        instrument_message(labeled, "/tuning/kbm", Path::new("")),
+        current_message(labeled, "", "Standard tuning"),
+    ]
+}
+
+/// Like patchbays, the instrument gets the path without extension.
+fn instrument_message(labeled: &LabeledMessage, addr: &str, path: &Path) -> LabeledMessage {
+    LabeledMessage::new(
+        labeled.controller(),
+        labeled.instrument(),
+        osc::msg(
+            addr,
+            vec![OscType::String(
+                path.with_extension("").to_string_lossy().to_string(),
+            )],
+        ),
+    )
+}
+
+fn current_message(labeled: &LabeledMessage, name: &str, description: &str) -> LabeledMessage {
+    LabeledMessage::new(
+        labeled.instrument(),
+        labeled.controller(),
+        osc::msg(
+            "/sys/tuning/current",
+            vec![
+                OscType::String(name.to_string()),
+                OscType::String(description.to_string()),
+            ],
+        ),
+    )
+}
+
+fn index_tunings(roots: &[PathBuf]) -> Vec<Tuning> {
+    let mut by_name: BTreeMap<String, Tuning> = BTreeMap::new();
+    for root in roots {
+        let mut files = vec![];
+        if let Err(e) = find_tuning_files(root, &mut files) {
+            warn!("Unable to index tunings in {:?}: {}", root, e);
+            continue;
+        }
+        for path in files {
+            let Some(name) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+                continue;
+            };
+            let tuning = by_name.entry(name.to_lowercase()).or_insert(Tuning {
+                name,
+                description: String::new(),
+                scl: None,
+                kbm: None,
+            });
+            let is_scale = path.extension().is_some_and(|ext| ext == "scl");
+            // The scale describes a tuning better than its keyboard mapping.
+            if is_scale || tuning.description.is_empty() {
+                if let Some(description) = read_description(&path, is_scale) {
+                    tuning.description = description;
+                }
+            }
+            match is_scale {
+                true => tuning.scl = Some(path),
+                false => tuning.kbm = Some(path),
+            }
+        }
+    }
+    info!("Indexed {} tunings", by_name.len());
+    by_name.into_values().collect()
+}
+
+/// Recursively collects all .scl and .kbm files below a directory.
+fn find_tuning_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)?.filter_map(Result::ok) {
+        let path = entry.path();
+        // The file type doesn't follow symlinks, so a link back up the tree can't loop.
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            find_tuning_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "scl" || ext == "kbm")
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// A .scl file has its description on the first line that is not a comment. A .kbm file
+/// has no description, so we use its first comment that isn't just the file name.
+fn read_description(path: &Path, is_scale: bool) -> Option<String> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let file_name = path.file_name()?.to_string_lossy().to_string();
+    let mut lines = contents.lines().map(str::trim);
+    match is_scale {
+        true => lines.find(|line| !line.starts_with('!')).map(String::from),
+        false => lines
+            .filter_map(|line| line.strip_prefix('!'))
+            .map(str::trim)
+            .find(|comment| !comment.is_empty() && *comment != file_name)
+            .map(String::from),
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    extension::{param_store, type_lookup},
+    labeler::LabeledMessage,
+    osc,
+};
+use log::debug;
+use rosc::OscType;
+use std::{
+    collections::VecDeque,
+    io::{self, Error},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use super::build_return_message;
+
+/// How many undo steps are kept.
+const UNDO_DEPTH: usize = 128;
+/// Changes to the same parameter closer together than this become one undo step,
+/// so a single fader move can be undone at once.
+const GROUP_WINDOW: Duration = Duration::from_millis(750);
+
+/// A parameter change made from the controller, possibly grouping several moves.
+#[derive(Debug, Clone)]
+struct Step {
+    addr: String,
+    before: OscType,
+    after: OscType,
+    changed_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Journal {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+}
+
+fn journal() -> &'static Mutex<Journal> {
+    static JOURNAL: OnceLock<Mutex<Journal>> = OnceLock::new();
+    JOURNAL.get_or_init(|| Mutex::new(Journal::default()))
+}
+
+/// Adds a parameter change headed for the instrument to the undo journal. Must be called
+/// before the new value is recorded in the parameter store, which holds the previous value.
+/// Changes to parameters we don't know the previous value of can't be undone.
+pub(crate) fn record(message: &osc::Message) {
+    if !message.addr.starts_with("/param/") {
+        return;
+    }
+    let Some(after) = message.args.first().cloned() else {
+        return;
+    };
+    let Ok(mut journal) = journal().lock() else {
+        return;
+    };
+    let now = Instant::now();
+
+    journal.redo.clear();
+    if let Some(last) = journal.undo.back_mut() {
+        if last.addr == message.addr && now.duration_since(last.changed_at) < GROUP_WINDOW {
+            last.after = after;
+            last.changed_at = now;
+            return;
+        }
+    }
+
+    let Some(before) = param_store::get(&message.addr) else {
+        debug!(
+            "No previous value for {}, change can't be undone.",
+            message.addr
+        );
+        return;
+    };
+    journal.undo.push_back(Step {
+        addr: message.addr.clone(),
+        before,
+        after,
+        changed_at: now,
+    });
+    if journal.undo.len() > UNDO_DEPTH {
+        journal.undo.pop_front();
+    }
+}
+
+/// Restores the value a parameter had before the last change.
+pub(super) fn undo(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let step = {
+        let mut journal = journal().lock().map_err(|_| poisoned())?;
+        let step = journal.undo.pop_back();
+        if let Some(step) = &step {
+            journal.redo.push(step.clone());
+        }
+        step
+    };
+    match step {
+        Some(step) => apply(labeled, "/sys/undo", &step.addr, step.before),
+        None => Ok(vec![build_return_message(
+            labeled,
+            String::from("/sys/undo"),
+            OscType::String(String::from("Nothing to undo.")),
+        )]),
+    }
+}
+
+/// Applies the last undone change again.
+pub(super) fn redo(labeled: LabeledMessage) -> Result<Vec<LabeledMessage>, io::Error> {
+    let step = {
+        let mut journal = journal().lock().map_err(|_| poisoned())?;
+        let step = journal.redo.pop();
+        if let Some(step) = &step {
+            journal.undo.push_back(step.clone());
+        }
+        step
+    };
+    match step {
+        Some(step) => apply(labeled, "/sys/redo", &step.addr, step.after),
+        None => Ok(vec![build_return_message(
+            labeled,
+            String::from("/sys/redo"),
+            OscType::String(String::from("Nothing to redo.")),
+        )]),
+    }
+}
+
+// ********
+// Helpers
+// ********
+
+/// Sends the value to the instrument and, translated where needed, to the controller.
+fn apply(
+    labeled: LabeledMessage,
+    reply_addr: &str,
+    addr: &str,
+    value: OscType,
+) -> Result<Vec<LabeledMessage>, io::Error> {
+    debug!("{} sets {} to {:?}", reply_addr, addr, value);
+    let message = osc::msg(addr, vec![value]);
+    param_store::record(&message);
+
+    let to_instrument =
+        LabeledMessage::new(labeled.controller(), labeled.instrument(), message.clone());
+    let to_controller = type_lookup(LabeledMessage::new(
+        labeled.instrument(),
+        labeled.controller(),
+        message,
+    ))?;
+    let reply = build_return_message(
+        labeled,
+        String::from(reply_addr),
+        OscType::String(String::from(addr)),
+    );
+    Ok(vec![to_instrument, to_controller, reply])
+}
+
+fn poisoned() -> Error {
+    Error::other("Undo journal lock was poisoned.")
+}
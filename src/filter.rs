@@ -0,0 +1,113 @@
+//! Allow and deny rules from the config. Messages are checked against them as they
+//! arrive, before the extensions see them, and dropped messages are counted per peer.
+
+use crate::{
+    osc::AddressPattern,
+    peer::{Peer, PeerKind},
+};
+use regex::Regex;
+use serde::Deserialize;
+
+/// Lets messages through or drops them. A rule matches addresses by OSC address
+/// `pattern` or by `regex`. It applies to messages going in `direction` and coming from
+/// the peer called `peer`, both any when left out.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "FilterRuleConfig")]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pub direction: Option<Direction>,
+    pub peer: Option<String>,
+    pub addresses: AddressMatcher,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ToInstrument,
+    ToController,
+}
+
+/// Patterns are checked and regexes compiled when the config is read.
+#[derive(Debug, Clone)]
+pub enum AddressMatcher {
+    Pattern(AddressPattern),
+    Regex(Regex),
+}
+
+/// A rule as written in the config.
+#[derive(Deserialize)]
+struct FilterRuleConfig {
+    action: FilterAction,
+    #[serde(default)]
+    direction: Option<Direction>,
+    #[serde(default)]
+    peer: Option<String>,
+    #[serde(default)]
+    pattern: Option<String>,
+    #[serde(default)]
+    regex: Option<String>,
+}
+
+impl TryFrom<FilterRuleConfig> for FilterRule {
+    type Error = String;
+
+    fn try_from(rule: FilterRuleConfig) -> Result<Self, Self::Error> {
+        let addresses = match (rule.pattern, rule.regex) {
+            (Some(pattern), None) => AddressPattern::new(&pattern)
+                .map(AddressMatcher::Pattern)
+                .map_err(|e| e.to_string())?,
+            (None, Some(regex)) => Regex::new(&regex)
+                .map(AddressMatcher::Regex)
+                .map_err(|e| format!("Invalid filter regex '{}': {}", regex, e))?,
+            _ => return Err(String::from("A filter needs either a pattern or a regex.")),
+        };
+        Ok(Self {
+            action: rule.action,
+            direction: rule.direction,
+            peer: rule.peer,
+            addresses,
+        })
+    }
+}
+
+impl FilterRule {
+    fn applies_to(&self, from: &Peer) -> bool {
+        let direction = match from.kind {
+            PeerKind::Controller => Direction::ToInstrument,
+            PeerKind::Instrument => Direction::ToController,
+        };
+        self.direction.is_none_or(|d| d == direction)
+            && self.peer.as_ref().is_none_or(|name| *name == from.name)
+    }
+}
+
+impl AddressMatcher {
+    fn is_match(&self, addr: &str) -> bool {
+        match self {
+            AddressMatcher::Pattern(pattern) => pattern.matches(addr),
+            AddressMatcher::Regex(regex) => regex.is_match(addr),
+        }
+    }
+}
+
+/// Whether a message from the peer passes the rules. Deny rules win over allow rules. Once
+/// there is an allow rule for the peer, only what it allows passes.
+pub(crate) fn allows(rules: &[FilterRule], from: &Peer, addr: &str) -> bool {
+    let mut allowed = None;
+    for rule in rules.iter().filter(|rule| rule.applies_to(from)) {
+        let matched = rule.addresses.is_match(addr);
+        match rule.action {
+            FilterAction::Deny if matched => return false,
+            FilterAction::Deny => {}
+            FilterAction::Allow => allowed = Some(allowed.unwrap_or(false) || matched),
+        }
+    }
+    allowed.unwrap_or(true)
+}
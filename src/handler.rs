@@ -1,15 +1,42 @@
 use crate::config::Config;
 use crate::{
+    capture,
     extension::extension_processor,
+    filter, journal,
     labeler::LabeledMessage,
+    monitor,
     osc::{self, *},
+    pcap,
     peer::{Peer, PeerKind},
     sender::send_message,
+    stats,
 };
 use log::{debug, info, warn};
-use std::{io, sync::Arc, thread::JoinHandle};
+use std::{io, sync::Arc, thread::JoinHandle, time::Instant};
 
 pub fn spawn_handler(config: Arc<Config>, peer_kind: PeerKind) -> JoinHandle<()> {
+    let recv_local =
+        bind_handler(&config, &peer_kind).expect("Failed to bind receiver to local ip.");
+    spawn_handler_on(config, peer_kind, recv_local)
+}
+
+/// Binds the receiver for packets from a peer, which fails when the port is taken.
+pub fn bind_handler(config: &Config, peer_kind: &PeerKind) -> io::Result<Receiver> {
+    let local_addr = match peer_kind {
+        PeerKind::Controller => config.controller.local_addr(),
+        PeerKind::Instrument => config.instrument.local_addr(),
+    };
+    receiver(local_addr.clone(), 1024)
+        .map_err(|e| io::Error::new(e.kind(), format!("Unable to bind {}: {}", local_addr, e)))
+}
+
+/// Handles the packets arriving at a receiver that is already bound, so whoever binds it
+/// knows the handler is listening.
+pub fn spawn_handler_on(
+    config: Arc<Config>,
+    peer_kind: PeerKind,
+    recv_local: Receiver,
+) -> JoinHandle<()> {
     let (peer_recv, peer_send) = match peer_kind {
         PeerKind::Controller => (
             Arc::new(config.controller.clone()),
@@ -21,27 +48,35 @@ pub fn spawn_handler(config: Arc<Config>, peer_kind: PeerKind) -> JoinHandle<()>
         ),
     };
 
-    // For debugging
-    let mut packages_received: usize = 0;
-
     // Spawn the thread that handles incoming packages
     std::thread::spawn(move || {
-        let recv_local =
-            receiver(peer_recv.local_addr(), 1024).expect("Failed to bind receiver to local ip.");
         info!("Receiver thread starting for {}", peer_recv.local_addr());
+        let capture_addr = pcap::local_address(&peer_recv);
 
         loop {
-            match recv_local.recv() {
-                Ok((packet, _)) => {
+            match recv_local.recv_bytes() {
+                Ok((bytes, source)) => {
+                    stats::packet_received(&peer_kind, bytes.len());
+                    capture::record(&peer_kind, &bytes);
+                    pcap::received(&bytes, source, &capture_addr);
+
                     // During a dryrun we only log the packagecount but don't actually handle packages.
                     if config.options.dryrun {
-                        packages_received += 1;
                         debug!(
                             "Packages received from {}: {}",
-                            peer_kind, packages_received
+                            peer_kind,
+                            stats::packets_received(&peer_kind)
                         );
                         continue;
                     }
+                    let packet = match decode(&bytes) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            stats::decode_error(&peer_kind);
+                            warn!("Failed to decode packet: {}", CommunicationError::from(e));
+                            continue;
+                        }
+                    };
                     match packet_sorter(
                         config.clone(),
                         peer_recv.clone(),
@@ -52,7 +87,7 @@ pub fn spawn_handler(config: Arc<Config>, peer_kind: PeerKind) -> JoinHandle<()>
                         Err(e) => warn!("Error handling packet: {}", e),
                     };
                 }
-                Err(e) => warn!("Failed to receive packet: {}", e.to_string()),
+                Err(e) => warn!("Failed to receive packet: {}", e),
             }
         }
     })
@@ -64,7 +99,15 @@ fn packet_sorter(
     peer_send: Arc<Peer>,
     packet: Packet,
 ) -> Result<(), io::Error> {
-    for message in packet.into_msgs() {
+    let messages = packet.into_msgs();
+    stats::messages_received(&peer_recv.kind, messages.len());
+    for message in messages {
+        if !filter::allows(&config.filters, &peer_recv, &message.addr) {
+            debug!("Filtered message from {peer_recv}: {}", message.addr);
+            stats::filtered(&peer_recv.kind);
+            continue;
+        }
+        let started = Instant::now();
         // If we don't want to use functional extensions, just pass the message on.
         match config.options.extend {
             true => message_processor(
@@ -73,8 +116,9 @@ fn packet_sorter(
                 peer_send.clone(),
                 message,
             )?,
-            false => send_message(message, peer_send.clone())?,
+            false => pass_through(peer_recv.clone(), peer_send.clone(), message)?,
         }
+        stats::processing_time(started.elapsed());
     }
 
     Ok(())
@@ -88,13 +132,59 @@ fn message_processor(
 ) -> Result<(), io::Error> {
     debug!("Received message from {peer_recv}: {:?}", message);
 
-    let labeled_message = LabeledMessage::new(peer_recv, peer_send, message);
-
-    let processed_message = extension_processor(config, labeled_message)
-        .map_err(|e| io::Error::new(e.kind(), format!("Error in extension processor: {}", e)))?;
+    // Only copy the message when the monitor or journal shows what became of it.
+    let original = (monitor::active() || journal::active()).then(|| message.clone());
+    journal::begin();
+    let labeled_message = LabeledMessage::new(peer_recv.clone(), peer_send.clone(), message);
 
-    send_message(processed_message.message, processed_message.peer_send)
-        .map_err(|e| io::Error::new(e.kind(), format!("Error sending message: {}", e)))?;
+    let processed_messages = extension_processor(config, labeled_message)
+        .map_err(|e| io::Error::new(e.kind(), format!("Error in extension processor: {}", e)));
+    let Some(original) = original else {
+        return send_all(processed_messages?);
+    };
+    let after = processed_messages.as_ref().cloned().unwrap_or_default();
+    let result = processed_messages.and_then(send_all);
+    monitor::observe(&peer_recv.kind, &original, &after);
+    journal::record(
+        &peer_recv,
+        &peer_send,
+        &original,
+        &after,
+        result.as_ref().err(),
+    );
+    result
+}
 
+fn send_all(processed_messages: Vec<LabeledMessage>) -> Result<(), io::Error> {
+    for processed_message in processed_messages {
+        send_message(processed_message.message, processed_message.peer_send)
+            .map_err(|e| io::Error::new(e.kind(), format!("Error sending message: {}", e)))?;
+    }
     Ok(())
 }
+
+/// Sends a message on as is, when extensions are off.
+fn pass_through(
+    peer_recv: Arc<Peer>,
+    peer_send: Arc<Peer>,
+    message: osc::Message,
+) -> Result<(), io::Error> {
+    if !monitor::active() && !journal::active() {
+        return send_message(message, peer_send);
+    }
+    let after = [LabeledMessage::new(
+        peer_recv.clone(),
+        peer_send.clone(),
+        message.clone(),
+    )];
+    let result = send_message(message, peer_send.clone());
+    monitor::observe(&peer_recv.kind, &after[0].message, &after);
+    journal::record(
+        &peer_recv,
+        &peer_send,
+        &after[0].message,
+        &after,
+        result.as_ref().err(),
+    );
+    result
+}
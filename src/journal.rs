@@ -0,0 +1,226 @@
+//! Writes a JSON object per handled message to a journal file, so a session's traffic can
+//! be analysed afterwards with tools like jq. Each entry has the message as it arrived,
+//! what was sent in its place, which extensions acted on it and any error.
+
+use crate::{
+    config::JournalConfig,
+    labeler::LabeledMessage,
+    osc,
+    peer::{Peer, PeerKind},
+    rotation::rotate_files,
+};
+use log::{debug, info, warn};
+use rosc::OscType;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Error, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, Once, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Entries are buffered and written out this often, so handlers don't wait for the disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct JournalWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: f64,
+    from: PeerRef<'a>,
+    to: PeerRef<'a>,
+    addr: &'a str,
+    args: Vec<Value>,
+    /// The messages sent in place of this one, empty when it was consumed.
+    transformed: Vec<Transformed<'a>>,
+    extensions: Vec<&'static str>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PeerRef<'a> {
+    kind: &'a PeerKind,
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct Transformed<'a> {
+    to: PeerRef<'a>,
+    addr: &'a str,
+    args: Vec<Value>,
+}
+
+fn journal() -> &'static Mutex<Option<JournalWriter>> {
+    static JOURNAL: OnceLock<Mutex<Option<JournalWriter>>> = OnceLock::new();
+    JOURNAL.get_or_init(|| Mutex::new(None))
+}
+
+thread_local! {
+    /// The extensions that acted on the message this thread is handling.
+    static ACTED: RefCell<Option<Vec<&'static str>>> = const { RefCell::new(None) };
+}
+
+/// Starts appending to the given file, or the file from the config.
+pub(crate) fn start(path: Option<&Path>, config: &JournalConfig) -> io::Result<()> {
+    let Some(path) = path.or(config.file.as_deref()) else {
+        return Ok(());
+    };
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let writer = JournalWriter {
+        written: file.metadata()?.len(),
+        writer: BufWriter::new(file),
+        path: path.to_path_buf(),
+        max_bytes: config.max_size_mb.max(1) * 1024 * 1024,
+        max_files: config.max_files,
+    };
+    *lock()? = Some(writer);
+    info!("Writing message journal to {:?}", path);
+    static FLUSHER: Once = Once::new();
+    FLUSHER.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(FLUSH_INTERVAL);
+            if let Ok(mut guard) = journal().lock() {
+                if let Some(Err(e)) = guard.as_mut().map(|writer| writer.writer.flush()) {
+                    warn!("Failed to write journal, journal stopped: {}", e);
+                    *guard = None;
+                }
+            }
+        });
+    });
+    Ok(())
+}
+
+/// Writes out what is buffered and closes the journal. Returns whether one was open.
+pub(crate) fn stop() -> io::Result<bool> {
+    let writer = lock()?.take();
+    if let Some(mut writer) = writer {
+        writer.writer.flush()?;
+        info!("Stopped writing message journal to {:?}", writer.path);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+pub(crate) fn active() -> bool {
+    matches!(journal().lock().as_deref(), Ok(Some(_)))
+}
+
+/// Starts noting which extensions act on the message this thread is about to handle.
+pub(crate) fn begin() {
+    if active() {
+        ACTED.with(|acted| *acted.borrow_mut() = Some(vec![]));
+    }
+}
+
+/// Called for every extension hit, only noted between `begin` and `record`.
+pub(crate) fn extension_acted(name: &'static str) {
+    ACTED.with(|acted| {
+        if let Some(acted) = acted.borrow_mut().as_mut() {
+            if !acted.contains(&name) {
+                acted.push(name);
+            }
+        }
+    });
+}
+
+/// Adds an entry for a message that came from `from` on its way to `to`.
+pub(crate) fn record(
+    from: &Peer,
+    to: &Peer,
+    original: &osc::Message,
+    after: &[LabeledMessage],
+    error: Option<&Error>,
+) {
+    let extensions = ACTED.with(|acted| acted.borrow_mut().take().unwrap_or_default());
+    let Ok(mut guard) = journal().lock() else {
+        return;
+    };
+    let Some(writer) = guard.as_mut() else {
+        return;
+    };
+    let entry = Entry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        from: peer_ref(from),
+        to: peer_ref(to),
+        addr: &original.addr,
+        args: json_args(&original.args),
+        transformed: after
+            .iter()
+            .map(|labeled| Transformed {
+                to: peer_ref(&labeled.peer_send),
+                addr: &labeled.message.addr,
+                args: json_args(&labeled.message.args),
+            })
+            .collect(),
+        extensions,
+        error: error.map(|e| e.to_string()),
+    };
+    if let Err(e) = write_entry(writer, &entry) {
+        warn!("Failed to write journal entry, journal stopped: {}", e);
+        *guard = None;
+    }
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> io::Result<MutexGuard<'static, Option<JournalWriter>>> {
+    journal()
+        .lock()
+        .map_err(|_| Error::other("Journal lock was poisoned."))
+}
+
+fn write_entry(writer: &mut JournalWriter, entry: &Entry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry).map_err(Error::other)?;
+    line.push(b'\n');
+    if writer.written > 0 && writer.written + line.len() as u64 > writer.max_bytes {
+        writer.writer.flush()?;
+        rotate_files(&writer.path, writer.max_files)?;
+        debug!("Rotated journal file {:?}", writer.path);
+        writer.writer = BufWriter::new(File::create(&writer.path)?);
+        writer.written = 0;
+    }
+    writer.writer.write_all(&line)?;
+    writer.written += line.len() as u64;
+    Ok(())
+}
+
+fn peer_ref(peer: &Peer) -> PeerRef<'_> {
+    PeerRef {
+        kind: &peer.kind,
+        name: &peer.name,
+    }
+}
+
+fn json_args(args: &[OscType]) -> Vec<Value> {
+    args.iter()
+        .map(|arg| match arg {
+            // Going through the shortest decimal keeps 0.1 from turning into 0.10000000149.
+            OscType::Float(value) => value
+                .to_string()
+                .parse::<f64>()
+                .map_or(Value::Null, Value::from),
+            OscType::Double(value) => Value::from(*value),
+            OscType::Int(value) => Value::from(*value),
+            OscType::Long(value) => Value::from(*value),
+            OscType::String(value) => Value::from(value.as_str()),
+            OscType::Bool(value) => Value::from(*value),
+            OscType::Nil => Value::Null,
+            other => Value::from(format!("{:?}", other)),
+        })
+        .collect()
+}
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use crate::osc::Message;
-use crate::peer::Peer;
+use crate::peer::{Peer, PeerKind};
 
 #[derive(Clone)]
 pub struct LabeledMessage {
@@ -18,4 +18,20 @@ impl LabeledMessage {
             peer_send,
         }
     }
+
+    /// The instrument taking part in this exchange, whichever way the message is going.
+    pub fn instrument(&self) -> Arc<Peer> {
+        match self.peer_send.kind {
+            PeerKind::Instrument => self.peer_send.clone(),
+            PeerKind::Controller => self.peer_recv.clone(),
+        }
+    }
+
+    /// The controller taking part in this exchange, whichever way the message is going.
+    pub fn controller(&self) -> Arc<Peer> {
+        match self.peer_send.kind {
+            PeerKind::Controller => self.peer_send.clone(),
+            PeerKind::Instrument => self.peer_recv.clone(),
+        }
+    }
 }
@@ -1,16 +1,38 @@
-use crate::{config::read_config_from_file, handler::spawn_handler, peer::PeerKind};
+use crate::{
+    benchmark::BenchmarkSettings,
+    capture::start_recording,
+    config::read_config_from_file,
+    extension::system::{
+        latency, modulator, notes,
+        patchbay::{export_archive, import_archive, ImportMode},
+    },
+    handler::{bind_handler, spawn_handler, spawn_handler_on},
+    peer::PeerKind,
+    replay::{parse_speed, replay, ReplayTarget},
+};
 use clap::{value_parser, Arg, ArgMatches, Command};
 use config::Config;
 use log::{info, warn};
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
+mod benchmark;
+mod capture;
 mod config;
 mod extension;
+mod filter;
 mod handler;
+mod journal;
 mod labeler;
+mod metrics;
+mod monitor;
 mod osc;
+mod pcap;
 mod peer;
+mod replay;
+mod rotation;
 mod sender;
+mod stats;
+#[cfg(test)]
 mod tests;
 
 fn main() {
@@ -19,7 +41,60 @@ fn main() {
     let config = create_config_arc(&matches);
 
     // Will proceed with tests and not run main program.
-    run_tests(&matches);
+    run_tests(&config, &matches);
+
+    // Subcommands do their work and exit without running the handlers.
+    if let Some(("patchbay", sub_matches)) = matches.subcommand() {
+        run_patchbay_command(&config, sub_matches);
+        return;
+    }
+    if let Some(("replay", sub_matches)) = matches.subcommand() {
+        run_replay_command(config, sub_matches);
+        return;
+    }
+
+    if let Some(pcap_file) = matches.get_one::<PathBuf>("pcap") {
+        if let Err(e) = pcap::start(Some(pcap_file), &config.pcap) {
+            panic!("Unable to capture to {:?}: {}", pcap_file, e)
+        }
+    }
+
+    if let Some(capture_file) = matches.get_one::<PathBuf>("record") {
+        if let Err(e) = start_recording(capture_file) {
+            panic!("Unable to record to {:?}: {}", capture_file, e)
+        }
+    }
+
+    let journal_file = matches.get_one::<PathBuf>("journal");
+    if let Err(e) = journal::start(journal_file.map(PathBuf::as_path), &config.journal) {
+        panic!("Unable to write the message journal: {}", e)
+    }
+
+    // Modulators, the held note watchdog and the latency monitor are extended features
+    // that send to the peers by themselves.
+    if config.options.extend && !config.options.dryrun {
+        modulator::start(config.clone());
+        notes::start_watchdog(config.clone());
+        latency::start(config.clone());
+    }
+
+    if let Err(e) = metrics::start(config.clone()) {
+        panic!("Unable to serve metrics: {}", e)
+    }
+
+    // Handlers run until arcflash is interrupted, so that is where we shut down.
+    if let Err(e) = ctrlc::set_handler(|| {
+        shut_down();
+        std::process::exit(0);
+    }) {
+        warn!("Unable to handle interrupts: {}", e);
+    }
+
+    // The monitor watches the handlers from the main thread until the user quits.
+    let observations = match matches.subcommand_name() {
+        Some("monitor") => Some(monitor::start()),
+        _ => None,
+    };
 
     info!("Spawning handler threads.");
 
@@ -27,6 +102,16 @@ fn main() {
     let t1 = spawn_handler(config.clone(), PeerKind::Instrument);
     let t2 = spawn_handler(config.clone(), PeerKind::Controller);
 
+    if let Some(observations) = observations {
+        let result = monitor::run(observations);
+        shut_down();
+        if let Err(e) = result {
+            eprintln!("Monitor failed: {}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
     if t1.join().is_err() {
         warn!("Thread 1 error.")
     };
@@ -34,7 +119,19 @@ fn main() {
         warn!("Thread 2 error.")
     };
 
+    shut_down();
+}
+
+/// Log the statistics and finish any capture in progress.
+fn shut_down() {
     info!("Shutting down.");
+    stats::log_summary();
+    if let Err(e) = pcap::stop() {
+        warn!("Unable to finish pcap capture: {}", e);
+    }
+    if let Err(e) = journal::stop() {
+        warn!("Unable to finish message journal: {}", e);
+    }
 }
 
 /// Startup logging and handle output to file
@@ -46,7 +143,8 @@ fn init_logger(matches: &ArgMatches) {
         env_logger::Builder::from_env(env)
             .target(env_logger::Target::Pipe(Box::new(file)))
             .init();
-    } else {
+    } else if matches.subcommand_name() != Some("monitor") {
+        // The monitor takes over the terminal, so it only logs to a file.
         env_logger::init();
     }
 }
@@ -75,12 +173,106 @@ fn create_config_arc(matches: &ArgMatches) -> Arc<Config> {
     Arc::new(config)
 }
 
-fn run_tests(matches: &ArgMatches) {
-    if let Some(value) = matches.get_one::<bool>("test") {
-        if value == &true {
-            info!("Running tests.");
-            panic!("Not implemented.")
-            // tests::test_q_all_params();
+fn run_tests(config: &Config, matches: &ArgMatches) {
+    if matches.get_one::<bool>("test") != Some(&true) {
+        return;
+    }
+    let count = |id: &str| *matches.get_one::<usize>(id).expect("Has a default value.");
+    let settings = BenchmarkSettings {
+        extend: config.options.extend,
+        messages: count("bench_messages"),
+        rate: *matches
+            .get_one::<u32>("bench_rate")
+            .expect("Rate has a default value."),
+        bursts: count("bench_bursts"),
+        burst_size: count("bench_burst_size"),
+        json: matches
+            .get_one::<PathBuf>("bench_json")
+            .expect("JSON file has a default value.")
+            .clone(),
+    };
+    info!("Running tests.");
+    match benchmark::run(&settings) {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("Benchmark failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Export or import the patch cache as a single archive.
+fn run_patchbay_command(config: &Config, matches: &ArgMatches) {
+    let result = match matches.subcommand() {
+        Some(("export", sub_matches)) => {
+            let file = sub_matches
+                .get_one::<PathBuf>("file")
+                .expect("File is a required argument.");
+            export_archive(config, file)
+                .map(|count| format!("Exported {} patchbays to {:?}.", count, file))
+        }
+        Some(("import", sub_matches)) => {
+            let file = sub_matches
+                .get_one::<PathBuf>("file")
+                .expect("File is a required argument.");
+            sub_matches
+                .get_one::<String>("mode")
+                .expect("Mode has a default value.")
+                .parse::<ImportMode>()
+                .and_then(|mode| import_archive(config, file, mode))
+                .map(|count| format!("Imported {} patchbays from {:?}.", count, file))
+        }
+        _ => unreachable!("A patchbay subcommand is required."),
+    };
+
+    match result {
+        Ok(summary) => println!("{}", summary),
+        Err(e) => {
+            eprintln!("Patchbay command failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Replay a capture file into the handlers or straight to one of the peers.
+fn run_replay_command(config: Arc<Config>, matches: &ArgMatches) {
+    let file = matches
+        .get_one::<PathBuf>("file")
+        .expect("File is a required argument.");
+    let speed = *matches
+        .get_one::<f64>("speed")
+        .expect("Speed has a default value.");
+    let target = matches
+        .get_one::<String>("to")
+        .expect("Target has a default value.")
+        .parse::<ReplayTarget>()
+        .expect("Target values are checked by the parser.");
+
+    // Replaying into the pipeline needs handlers to receive the packets.
+    // Arcflash may already be running on the same ports.
+    if target == ReplayTarget::Pipeline {
+        for kind in [PeerKind::Instrument, PeerKind::Controller] {
+            match bind_handler(&config, &kind) {
+                Ok(receiver) => {
+                    spawn_handler_on(config.clone(), kind, receiver);
+                }
+                Err(e) => {
+                    eprintln!("Unable to replay into the handlers: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    match replay(&config, file, speed, target) {
+        Ok(count) => {
+            // Give the handlers a moment to forward the last packets.
+            std::thread::sleep(Duration::from_millis(500));
+            println!("Replayed {} packets from {:?}.", count, file);
+        }
+        Err(e) => {
+            eprintln!("Replay failed: {}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -126,6 +318,46 @@ fn read_command_line_args() -> ArgMatches {
                 .value_parser(value_parser!(bool))
                 .help("Perform tests on OSC peers and arcflash throughput."),
         )
+        .arg(
+            Arg::new("bench_messages")
+                .long("bench-messages")
+                .value_name("10000")
+                .default_value("10000")
+                .value_parser(value_parser!(usize))
+                .help("Parameter messages the controller sends during --tests."),
+        )
+        .arg(
+            Arg::new("bench_rate")
+                .long("bench-rate")
+                .value_name("0")
+                .default_value("0")
+                .value_parser(value_parser!(u32))
+                .help("Parameter messages per second during --tests, 0 for as fast as possible."),
+        )
+        .arg(
+            Arg::new("bench_bursts")
+                .long("bench-bursts")
+                .value_name("10")
+                .default_value("10")
+                .value_parser(value_parser!(usize))
+                .help("Times the controller asks for all parameters during --tests."),
+        )
+        .arg(
+            Arg::new("bench_burst_size")
+                .long("bench-burst-size")
+                .value_name("500")
+                .default_value("500")
+                .value_parser(value_parser!(usize))
+                .help("Parameters the instrument answers each request for all parameters with."),
+        )
+        .arg(
+            Arg::new("bench_json")
+                .long("bench-json")
+                .value_name("arcflash-benchmark.json")
+                .default_value("arcflash-benchmark.json")
+                .value_parser(value_parser!(PathBuf))
+                .help("Write the --tests results to this JSON file."),
+        )
         .arg(
             Arg::new("dryrun")
                 .short('d')
@@ -135,5 +367,91 @@ fn read_command_line_args() -> ArgMatches {
                 .value_parser(value_parser!(bool))
                 .help("Only receive messages don't send anything."),
         )
+        .arg(
+            Arg::new("record")
+                .short('r')
+                .long("record")
+                .value_name("session.afcap")
+                .value_parser(value_parser!(PathBuf))
+                .help("Record all received packets to a capture file for replay."),
+        )
+        .arg(
+            Arg::new("pcap")
+                .short('p')
+                .long("pcap")
+                .value_name("arcflash.pcap")
+                .value_parser(value_parser!(PathBuf))
+                .help("Capture all traffic to a pcap file for Wireshark."),
+        )
+        .arg(
+            Arg::new("journal")
+                .short('j')
+                .long("journal")
+                .value_name("arcflash.jsonl")
+                .value_parser(value_parser!(PathBuf))
+                .help("Write every message and what became of it to a JSON lines file."),
+        )
+        .subcommand(
+            Command::new("patchbay")
+                .about("Manage the patchbays in the patch cache.")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("export")
+                        .about("Pack all patchbays into a single tar archive.")
+                        .arg(
+                            Arg::new("file")
+                                .required(true)
+                                .value_name("patchbays.tar")
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Unpack a patchbay archive into the patch cache.")
+                        .arg(
+                            Arg::new("file")
+                                .required(true)
+                                .value_name("patchbays.tar")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("mode")
+                                .short('m')
+                                .long("mode")
+                                .default_value("merge")
+                                .value_parser(["merge", "replace"])
+                                .help("Merge with or replace the existing patchbays."),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("monitor")
+                .about("Forward as usual while showing the traffic live in the terminal."),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Replay a capture file made with --record.")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .value_name("session.afcap")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("speed")
+                        .short('s')
+                        .long("speed")
+                        .default_value("1.0")
+                        .value_parser(parse_speed)
+                        .help("Playback speed, 2.0 is twice as fast. 0 sends without delays."),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .default_value("pipeline")
+                        .value_parser(["pipeline", "instrument", "controller"])
+                        .help("Replay into the handlers, or straight to one of the peers."),
+                ),
+        )
         .get_matches()
 }
@@ -0,0 +1,314 @@
+//! A small HTTP server that serves the statistics in the Prometheus text format on
+//! `/metrics`, together with the system load and CPU speed.
+
+use crate::{config::Config, peer::Peer, stats};
+use log::{debug, info, warn};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Requests are served one at a time, so a client that stalls is cut off after this long.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Starts serving metrics if an address is configured.
+pub(crate) fn start(config: Arc<Config>) -> io::Result<()> {
+    let Some(listen) = &config.metrics.listen else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(listen)?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    serve(config, listener);
+    Ok(())
+}
+
+/// Answers requests on the listener in a thread of its own.
+pub(crate) fn serve(config: Arc<Config>, listener: TcpListener) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(&config, stream));
+            if let Err(e) = result {
+                debug!("Metrics request failed: {}", e);
+            }
+        }
+        warn!("Metrics server stopped.");
+    });
+}
+
+// ********
+// Helpers
+// ********
+
+fn respond(config: &Config, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // We don't need the headers, but the client expects us to read them.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render(config)),
+        _ => ("404 Not Found", "text/plain", String::from("Not found.\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// All metrics in the Prometheus text exposition format.
+fn render(config: &Config) -> String {
+    let mut out = String::new();
+    let Some(snapshot) = stats::snapshot() else {
+        return out;
+    };
+    let peers = [
+        ("controller", &snapshot.controller),
+        ("instrument", &snapshot.instrument),
+    ];
+
+    family(
+        &mut out,
+        "arcflash_uptime_seconds",
+        "gauge",
+        "Time since arcflash started.",
+    );
+    sample(
+        &mut out,
+        "arcflash_uptime_seconds",
+        "",
+        snapshot.uptime.as_secs_f64(),
+    );
+
+    family(
+        &mut out,
+        "arcflash_packets_received_total",
+        "counter",
+        "UDP packets received from each peer.",
+    );
+    for (peer, counters) in peers {
+        let labels = format!("peer=\"{}\"", peer);
+        sample(
+            &mut out,
+            "arcflash_packets_received_total",
+            &labels,
+            counters.packets_in as f64,
+        );
+    }
+    family(
+        &mut out,
+        "arcflash_messages_total",
+        "counter",
+        "OSC messages per peer and direction.",
+    );
+    for (peer, counters) in peers {
+        for (direction, messages) in [("in", counters.messages_in), ("out", counters.messages_out)]
+        {
+            let labels = format!("peer=\"{}\",direction=\"{}\"", peer, direction);
+            sample(
+                &mut out,
+                "arcflash_messages_total",
+                &labels,
+                messages as f64,
+            );
+        }
+    }
+    family(
+        &mut out,
+        "arcflash_bytes_total",
+        "counter",
+        "Bytes per peer and direction.",
+    );
+    for (peer, counters) in peers {
+        for (direction, bytes) in [("in", counters.bytes_in), ("out", counters.bytes_out)] {
+            let labels = format!("peer=\"{}\",direction=\"{}\"", peer, direction);
+            sample(&mut out, "arcflash_bytes_total", &labels, bytes as f64);
+        }
+    }
+    family(
+        &mut out,
+        "arcflash_filtered_total",
+        "counter",
+        "Messages from each peer dropped by filter rules.",
+    );
+    for (peer, counters) in peers {
+        let labels = format!("peer=\"{}\"", peer);
+        sample(
+            &mut out,
+            "arcflash_filtered_total",
+            &labels,
+            counters.filtered as f64,
+        );
+    }
+    family(
+        &mut out,
+        "arcflash_errors_total",
+        "counter",
+        "Errors per peer and kind.",
+    );
+    for (peer, counters) in peers {
+        for (kind, count) in [
+            ("decode", counters.decode_errors),
+            ("send", counters.send_errors),
+        ] {
+            let labels = format!("peer=\"{}\",kind=\"{}\"", peer, kind);
+            sample(&mut out, "arcflash_errors_total", &labels, count as f64);
+        }
+    }
+
+    family(
+        &mut out,
+        "arcflash_receive_queue_bytes",
+        "gauge",
+        "Bytes waiting in the receive buffer of the socket for each peer.",
+    );
+    for (name, peer) in [
+        ("controller", &config.controller),
+        ("instrument", &config.instrument),
+    ] {
+        if let Some(queued) = receive_queue(peer) {
+            let labels = format!("peer=\"{}\"", name);
+            sample(
+                &mut out,
+                "arcflash_receive_queue_bytes",
+                &labels,
+                queued as f64,
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "arcflash_extension_hits_total",
+        "counter",
+        "Messages each extension acted on.",
+    );
+    for (extension, hits) in &snapshot.extensions {
+        let labels = format!("extension=\"{}\"", extension);
+        sample(
+            &mut out,
+            "arcflash_extension_hits_total",
+            &labels,
+            *hits as f64,
+        );
+    }
+    family(
+        &mut out,
+        "arcflash_extension_seconds_total",
+        "counter",
+        "Time spent in each extension.",
+    );
+    for (extension, time) in &snapshot.extension_time {
+        let labels = format!("extension=\"{}\"", extension);
+        sample(
+            &mut out,
+            "arcflash_extension_seconds_total",
+            &labels,
+            time.as_secs_f64(),
+        );
+    }
+
+    family(
+        &mut out,
+        "arcflash_processing_seconds",
+        "summary",
+        "Time from decoding a message to sending the results.",
+    );
+    if let Some(latency) = &snapshot.latency {
+        for (quantile, value) in [
+            ("0.5", latency.p50),
+            ("0.9", latency.p90),
+            ("0.99", latency.p99),
+            ("1", latency.max),
+        ] {
+            let labels = format!("quantile=\"{}\"", quantile);
+            sample(
+                &mut out,
+                "arcflash_processing_seconds",
+                &labels,
+                value.as_secs_f64(),
+            );
+        }
+    }
+    sample(
+        &mut out,
+        "arcflash_processing_seconds_sum",
+        "",
+        snapshot.processing_time.as_secs_f64(),
+    );
+    sample(
+        &mut out,
+        "arcflash_processing_seconds_count",
+        "",
+        snapshot.processed as f64,
+    );
+
+    if let Ok(load) = sys_info::loadavg() {
+        family(
+            &mut out,
+            "arcflash_system_load",
+            "gauge",
+            "System load average.",
+        );
+        for (period, value) in [("1m", load.one), ("5m", load.five), ("15m", load.fifteen)] {
+            let labels = format!("period=\"{}\"", period);
+            sample(&mut out, "arcflash_system_load", &labels, value);
+        }
+    }
+    if let Ok(speed) = sys_info::cpu_speed() {
+        family(&mut out, "arcflash_cpu_speed_mhz", "gauge", "CPU speed.");
+        sample(&mut out, "arcflash_cpu_speed_mhz", "", speed as f64);
+    }
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: f64) {
+    let _ = match labels.is_empty() {
+        true => writeln!(out, "{} {}", name, value),
+        false => writeln!(out, "{}{{{}}} {}", name, labels, value),
+    };
+}
+
+/// Reads the receive queue of the UDP socket we bound for a peer from /proc, so this
+/// only works on Linux.
+fn receive_queue(peer: &Peer) -> Option<u64> {
+    let port = peer.local_port.parse::<u16>().ok()?;
+    ["/proc/net/udp", "/proc/net/udp6"]
+        .into_iter()
+        .filter_map(|file| std::fs::read_to_string(file).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    // Columns are: sl local_address rem_address st tx_queue:rx_queue ...
+                    let mut columns = line.split_whitespace().skip(1);
+                    let local = columns.next()?;
+                    let queues = columns.nth(2)?;
+                    let local_port = u16::from_str_radix(local.rsplit(':').next()?, 16).ok()?;
+                    let rx_queue = u64::from_str_radix(queues.split(':').nth(1)?, 16).ok()?;
+                    (local_port == port).then_some(rx_queue)
+                })
+                .collect::<Vec<_>>()
+        })
+        .next()
+}
@@ -0,0 +1,313 @@
+//! A live view of the traffic in the terminal for `arcflash monitor`. The handlers pass
+//! every message and what the extensions made of it to the monitor without waiting, and
+//! the monitor keeps a row per address, peer and direction.
+
+use crate::{
+    labeler::LabeledMessage,
+    osc::{self, AddressPattern},
+    peer::PeerKind,
+};
+use flume::{Receiver, Sender, TrySendError};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Row as TableRow, Table},
+    Frame,
+};
+use rosc::OscType;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Messages waiting for the monitor. When it falls behind, more are dropped, so
+/// forwarding never waits for the terminal.
+const QUEUE: usize = 4096;
+/// Rates are averaged over this window.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+/// How often the table is redrawn.
+const REFRESH: Duration = Duration::from_millis(100);
+
+/// A message as the handler received it and the messages the extensions made of it.
+pub(crate) struct Observation {
+    at: Instant,
+    from: PeerKind,
+    before: osc::Message,
+    after: Vec<osc::Message>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Row {
+    count: u64,
+    arrivals: VecDeque<Instant>,
+    value: String,
+    /// What the extensions made of the last message, if they changed it.
+    after: Option<String>,
+}
+
+/// Rows by direction and address.
+type Rows = BTreeMap<(String, String), Row>;
+
+#[derive(Default)]
+pub(crate) struct Monitor {
+    rows: Rows,
+    /// The rows as they were when the view was paused.
+    paused: Option<(Instant, Rows)>,
+    filter: Option<AddressPattern>,
+    /// The filter being typed.
+    input: Option<String>,
+    error: Option<String>,
+}
+
+fn tap() -> &'static OnceLock<Sender<Observation>> {
+    static TAP: OnceLock<Sender<Observation>> = OnceLock::new();
+    &TAP
+}
+
+fn dropped() -> &'static AtomicU64 {
+    static DROPPED: AtomicU64 = AtomicU64::new(0);
+    &DROPPED
+}
+
+/// Starts collecting observations from the handlers.
+pub(crate) fn start() -> Receiver<Observation> {
+    let (sender, receiver) = flume::bounded(QUEUE);
+    if tap().set(sender).is_err() {
+        panic!("The monitor can only be started once.");
+    }
+    receiver
+}
+
+/// Whether anyone is watching, so handlers only copy messages when needed.
+pub(crate) fn active() -> bool {
+    tap().get().is_some()
+}
+
+pub(crate) fn observe(from: &PeerKind, before: &osc::Message, after: &[LabeledMessage]) {
+    let Some(sender) = tap().get() else {
+        return;
+    };
+    let observation = Observation {
+        at: Instant::now(),
+        from: from.clone(),
+        before: before.clone(),
+        after: after
+            .iter()
+            .map(|labeled| labeled.message.clone())
+            .collect(),
+    };
+    if let Err(TrySendError::Full(_)) = sender.try_send(observation) {
+        dropped().fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Shows the table until the user quits.
+pub(crate) fn run(observations: Receiver<Observation>) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut monitor = Monitor::default();
+    let result = loop {
+        for observation in observations.try_iter() {
+            monitor.record(observation);
+        }
+        if let Err(e) = terminal.draw(|frame| monitor.draw(frame)) {
+            break Err(e);
+        }
+        match event::poll(REFRESH) {
+            Ok(false) => continue,
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    if !monitor.key(key) {
+                        break Ok(());
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            },
+            Err(e) => break Err(e),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+impl Monitor {
+    pub(crate) fn record(&mut self, observation: Observation) {
+        let direction = match observation.from {
+            PeerKind::Controller => "controller → instrument",
+            PeerKind::Instrument => "instrument → controller",
+        };
+        let row = self
+            .rows
+            .entry((String::from(direction), observation.before.addr.clone()))
+            .or_default();
+        row.count += 1;
+        row.arrivals.push_back(observation.at);
+        while row
+            .arrivals
+            .front()
+            .is_some_and(|at| observation.at.duration_since(*at) > RATE_WINDOW)
+        {
+            row.arrivals.pop_front();
+        }
+        row.value = format_args(&observation.before.args);
+        row.after = describe_change(&observation.before, &observation.after);
+    }
+
+    /// Handles a key press, returns false to quit.
+    pub(crate) fn key(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Enter => {
+                    let pattern = self.input.take().unwrap_or_default();
+                    self.error = None;
+                    self.filter = match pattern.is_empty() {
+                        true => None,
+                        false => match AddressPattern::new(&pattern) {
+                            Ok(pattern) => Some(pattern),
+                            Err(e) => {
+                                self.error = Some(e.to_string());
+                                self.filter.take()
+                            }
+                        },
+                    };
+                }
+                KeyCode::Esc => self.input = None,
+                _ => {}
+            }
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('p') | KeyCode::Char(' ') => {
+                self.paused = match self.paused {
+                    Some(_) => None,
+                    None => Some((Instant::now(), self.rows.clone())),
+                };
+            }
+            KeyCode::Char('/') => {
+                self.input = Some(
+                    self.filter
+                        .as_ref()
+                        .map(|pattern| pattern.as_str().to_string())
+                        .unwrap_or_default(),
+                );
+            }
+            KeyCode::Char('c') => self.rows.clear(),
+            _ => {}
+        }
+        true
+    }
+
+    pub(crate) fn draw(&self, frame: &mut Frame) {
+        let [table_area, status_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let (now, rows) = match &self.paused {
+            Some((at, rows)) => (*at, rows),
+            None => (Instant::now(), &self.rows),
+        };
+
+        let visible: Vec<TableRow> = rows
+            .iter()
+            .filter(|((_, addr), _)| self.filter.as_ref().is_none_or(|f| f.matches(addr)))
+            .map(|((direction, addr), row)| {
+                let recent = row
+                    .arrivals
+                    .iter()
+                    .filter(|at| now.saturating_duration_since(**at) <= RATE_WINDOW)
+                    .count();
+                TableRow::new(vec![
+                    direction.clone(),
+                    addr.clone(),
+                    row.value.clone(),
+                    row.after.clone().unwrap_or_default(),
+                    format!("{:.1}", recent as f32 / RATE_WINDOW.as_secs_f32()),
+                    row.count.to_string(),
+                ])
+            })
+            .collect();
+        let count = visible.len();
+        let header = TableRow::new(["Direction", "Address", "Value", "After", "Rate/s", "Count"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let widths = [
+            Constraint::Length(23),
+            Constraint::Percentage(30),
+            Constraint::Percentage(20),
+            Constraint::Percentage(30),
+            Constraint::Length(7),
+            Constraint::Length(8),
+        ];
+        frame.render_widget(Table::new(visible, widths).header(header), table_area);
+
+        let status = match (&self.input, &self.error) {
+            (Some(input), _) => format!("Filter (OSC address pattern): {}_", input),
+            (None, Some(error)) => format!("Invalid filter: {}", error),
+            (None, None) => {
+                let mut status =
+                    format!("q quit  p pause  / filter  c clear  |  {} addresses", count);
+                if let Some(filter) = &self.filter {
+                    status.push_str(&format!(" matching {}", filter.as_str()));
+                }
+                if self.paused.is_some() {
+                    status.push_str("  |  PAUSED");
+                }
+                let dropped = dropped().load(Ordering::Relaxed);
+                if dropped > 0 {
+                    status.push_str(&format!("  |  {} not shown", dropped));
+                }
+                status
+            }
+        };
+        frame.render_widget(Line::from(status), status_area);
+    }
+}
+
+// ********
+// Helpers
+// ********
+
+/// Describes what the extensions did to a message, nothing if they passed it on as is.
+fn describe_change(before: &osc::Message, after: &[osc::Message]) -> Option<String> {
+    match after {
+        [] => Some(String::from("(consumed)")),
+        [message] if message == before => None,
+        messages => Some(
+            messages
+                .iter()
+                .map(|message| match message.addr == before.addr {
+                    true => format_args(&message.args),
+                    false => format!("{} {}", message.addr, format_args(&message.args)),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    }
+}
+
+fn format_args(args: &[OscType]) -> String {
+    args.iter()
+        .map(|arg| match arg {
+            OscType::Float(value) => format!("{:.3}", value),
+            OscType::Double(value) => format!("{:.3}", value),
+            OscType::Int(value) => value.to_string(),
+            OscType::Long(value) => value.to_string(),
+            OscType::String(value) => format!("\"{}\"", value),
+            OscType::Bool(value) => value.to_string(),
+            other => format!("{:?}", other),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,27 @@
+//! Numbers from OSC arguments, whichever numeric type the peer sent them as.
+
+use super::Type;
+
+/// Any number, or a bool as 0 or 1.
+pub fn as_f32(arg: &Type) -> Option<f32> {
+    match arg {
+        Type::Float(value) => Some(*value),
+        Type::Double(value) => Some(*value as f32),
+        Type::Int(value) => Some(*value as f32),
+        Type::Long(value) => Some(*value as f32),
+        Type::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
+
+/// Any number rounded towards zero, or a string with a whole number.
+pub fn as_i64(arg: &Type) -> Option<i64> {
+    match arg {
+        Type::Int(value) => Some(*value as i64),
+        Type::Long(value) => Some(*value),
+        Type::Float(value) => Some(*value as i64),
+        Type::Double(value) => Some(*value as i64),
+        Type::String(value) => value.parse::<i64>().ok(),
+        _ => None,
+    }
+}
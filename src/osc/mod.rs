@@ -10,17 +10,21 @@ use rosc::OscPacket;
 //
 // Remove `Osc` prefix as items are already namespaced via a module, e.g. `OscMessage` becomes
 // `nannou_osc::Message`.
+pub use self::args::{as_f32, as_i64};
+pub use self::pattern::{is_pattern, matches, AddressPattern};
 pub use self::recv::Receiver;
 #[doc(inline)]
+#[allow(unused_imports)]
 pub use self::rosc::{
     decoder, encoder, OscBundle as Bundle, OscColor as Color, OscError as Error,
     OscMessage as Message, OscMidiMessage as MidiMessage, OscType as Type,
 };
 pub use self::send::Sender;
 
-use std;
 use std::net::{Ipv4Addr, SocketAddr};
 
+pub mod args;
+pub mod pattern;
 pub mod recv;
 pub mod send;
 
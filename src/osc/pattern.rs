@@ -0,0 +1,196 @@
+//! OSC 1.0 address pattern matching. In a pattern `?` matches any single character and
+//! `*` any sequence of characters, both within one part of the address. `[a-z]` matches a
+//! character in the set, `[!a-z]` one outside it, and `{foo,bar}` either string.
+
+use serde::Deserialize;
+use std::io;
+
+/// A pattern checked to be well formed, for patterns that come from users. Patterns in the
+/// config are checked when it is read.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AddressPattern(String);
+
+impl TryFrom<String> for AddressPattern {
+    type Error = io::Error;
+
+    fn try_from(pattern: String) -> io::Result<Self> {
+        Self::new(&pattern)
+    }
+}
+
+impl AddressPattern {
+    /// Returns an error if the pattern doesn't start with `/` or leaves a `[` or `{` open.
+    pub fn new(pattern: &str) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid address pattern '{}': {}", pattern, reason),
+            ))
+        };
+        if !pattern.starts_with('/') {
+            return invalid("it should start with /.");
+        }
+        if pattern.len() > MAX_PATTERN_LEN {
+            return invalid(&format!(
+                "it is longer than {} characters.",
+                MAX_PATTERN_LEN
+            ));
+        }
+        let mut open = None;
+        for c in pattern.chars() {
+            match (open, c) {
+                (None, '[') => open = Some(']'),
+                (None, '{') => open = Some('}'),
+                (Some(close), c) if c == close => open = None,
+                (Some(_), '[' | '{' | '/') => {
+                    return invalid("brackets can't be nested or span parts.")
+                }
+                _ => {}
+            }
+        }
+        if let Some(close) = open {
+            return invalid(&format!("missing '{}'.", close));
+        }
+        Ok(Self(pattern.to_string()))
+    }
+
+    pub fn matches(&self, addr: &str) -> bool {
+        matches(&self.0, addr)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Whether the address has any pattern characters, so it may stand for several addresses.
+pub fn is_pattern(addr: &str) -> bool {
+    addr.contains(['?', '*', '[', ']', '{', '}'])
+}
+
+/// Longer patterns match nothing, so a pattern from a peer can't keep a handler busy.
+pub const MAX_PATTERN_LEN: usize = 256;
+
+/// Whether the whole address matches the pattern. A malformed pattern matches nothing.
+/// Nothing in a pattern matches `/`, so the address is matched one part at a time.
+pub fn matches(pattern: &str, addr: &str) -> bool {
+    if pattern.len() > MAX_PATTERN_LEN {
+        return false;
+    }
+    let (mut patterns, mut parts) = (pattern.split('/'), addr.split('/'));
+    loop {
+        match (patterns.next(), parts.next()) {
+            (None, None) => return true,
+            (Some(pattern), Some(part)) if match_part(pattern.as_bytes(), part.as_bytes()) => {}
+            _ => return false,
+        }
+    }
+}
+
+enum Token<'a> {
+    Char(u8),
+    Any,
+    Star,
+    Set(&'a [u8]),
+    Alternatives(&'a [u8]),
+}
+
+/// Splits a part of a pattern into tokens, `None` when a `[` or `{` isn't closed.
+fn tokens(pattern: &[u8]) -> Option<Vec<Token<'_>>> {
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < pattern.len() {
+        let token = match pattern[i] {
+            b'?' => Token::Any,
+            // Several stars in a row match the same as one.
+            b'*' if matches!(tokens.last(), Some(Token::Star)) => {
+                i += 1;
+                continue;
+            }
+            b'*' => Token::Star,
+            open @ (b'[' | b'{') => {
+                let close = if open == b'[' { b']' } else { b'}' };
+                let end = i + pattern[i..].iter().position(|c| *c == close)?;
+                let inner = &pattern[i + 1..end];
+                i = end;
+                match open {
+                    b'[' => Token::Set(inner),
+                    _ => Token::Alternatives(inner),
+                }
+            }
+            c => Token::Char(c),
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    Some(tokens)
+}
+
+/// Follows every way the tokens can consume the part at once, instead of backtracking, so
+/// the work grows with the lengths of pattern and part rather than exponentially.
+fn match_part(pattern: &[u8], part: &[u8]) -> bool {
+    let Some(tokens) = tokens(pattern) else {
+        return false;
+    };
+    // Which positions in the part the tokens so far can end at.
+    let mut reached = vec![false; part.len() + 1];
+    reached[0] = true;
+    for token in &tokens {
+        let mut next = vec![false; part.len() + 1];
+        for at in (0..=part.len()).filter(|at| reached[*at]) {
+            match token {
+                Token::Char(c) => {
+                    if part.get(at) == Some(c) {
+                        next[at + 1] = true;
+                    }
+                }
+                Token::Any => {
+                    if at < part.len() {
+                        next[at + 1] = true;
+                    }
+                }
+                Token::Star => {
+                    next[at..]
+                        .iter_mut()
+                        .for_each(|reachable| *reachable = true);
+                    break;
+                }
+                Token::Set(set) => {
+                    if part.get(at).is_some_and(|c| in_set(set, *c)) {
+                        next[at + 1] = true;
+                    }
+                }
+                Token::Alternatives(alternatives) => {
+                    for alternative in alternatives.split(|c| *c == b',') {
+                        if part[at..].starts_with(alternative) {
+                            next[at + alternative.len()] = true;
+                        }
+                    }
+                }
+            }
+        }
+        reached = next;
+    }
+    reached[part.len()]
+}
+
+/// Whether the character is in a set like `a-z0`, or outside it for `!a-z0`.
+fn in_set(set: &[u8], c: u8) -> bool {
+    let (negated, set) = match set.first() {
+        Some(b'!') => (true, &set[1..]),
+        _ => (false, set),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if set.get(i + 1) == Some(&b'-') && i + 2 < set.len() {
+            found |= (set[i]..=set[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
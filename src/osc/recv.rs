@@ -129,6 +129,16 @@ impl Receiver<Unconnected> {
         Ok(receiver)
     }
 
+    /// Receives on a socket that is already bound, with a buffer of `mtu` bytes.
+    pub fn from_socket(socket: UdpSocket, mtu: usize) -> Self {
+        Receiver {
+            buffer: Mutex::new(vec![0; mtu]),
+            socket,
+            non_blocking: AtomicBool::new(DEFAULT_NON_BLOCKING),
+            mode: Unconnected,
+        }
+    }
+
     /// The same as `bind_to`, but assumes that the IP address is `0.0.0.0`.
     ///
     /// The resulting socket address will be `0.0.0.0:<port>`.
@@ -219,6 +229,25 @@ impl Receiver<Unconnected> {
         Ok((packet, addr))
     }
 
+    /// Waits for the next UDP packet and returns its raw bytes along with the source address,
+    /// without decoding them. Use `decode` to turn the bytes into a `Packet`.
+    ///
+    /// If the socket is currently in non-blocking mode, this method will first switch the socket
+    /// to blocking.
+    ///
+    /// This will return a `CommunicationError` if:
+    ///
+    /// - Switching the socket from "non_blocking" to "blocking" fails,
+    /// - The Mutex around the inner buffer (used to collect bytes) was poisoned,
+    /// - The MTU was not large enough to receive a UDP packet or
+    /// - The inner `UdpSocket::recv` call fails.
+    pub fn recv_bytes(&self) -> Result<(Vec<u8>, SocketAddr), CommunicationError> {
+        self.switch_to_blocking()?;
+        let buffer = &mut *self.buffer.lock()?;
+        let (len, addr) = self.socket.recv_from(buffer)?;
+        Ok((buffer[..len].to_vec(), addr))
+    }
+
     /// Checks for a pending OSC packet and returns `Ok(Some)` if there is one waiting along with
     /// the source address.
     ///
@@ -254,7 +283,7 @@ impl Receiver<Unconnected> {
     ///
     /// Each call to `next` will block until the next packet is received or until some error
     /// occurs.
-    pub fn iter(&self) -> Iter<Unconnected> {
+    pub fn iter(&self) -> Iter<'_, Unconnected> {
         Iter { receiver: self }
     }
 
@@ -262,7 +291,7 @@ impl Receiver<Unconnected> {
     ///
     /// Each call to `next` will only return `Some` while there are pending packets and will return
     /// `None` otherwise.
-    pub fn try_iter(&self) -> TryIter<Unconnected> {
+    pub fn try_iter(&self) -> TryIter<'_, Unconnected> {
         TryIter { receiver: self }
     }
 }
@@ -324,7 +353,7 @@ impl Receiver<Connected> {
     ///
     /// Each call to `next` will block until the next packet is received or until some error
     /// occurs.
-    pub fn iter(&self) -> Iter<Connected> {
+    pub fn iter(&self) -> Iter<'_, Connected> {
         Iter { receiver: self }
     }
 
@@ -332,7 +361,7 @@ impl Receiver<Connected> {
     ///
     /// Each call to `next` will only return `Some` while there are pending packets and will return
     /// `None` otherwise.
-    pub fn try_iter(&self) -> TryIter<Connected> {
+    pub fn try_iter(&self) -> TryIter<'_, Connected> {
         TryIter { receiver: self }
     }
 }
@@ -0,0 +1,233 @@
+//! Writes the UDP traffic arcflash receives and sends to pcap files, so sessions can be
+//! inspected in Wireshark. Packets are stored as raw IPv4 with synthesised IP and UDP
+//! headers, the OSC payload is exactly what went over the wire.
+
+use crate::{
+    config::PcapConfig,
+    osc::{self, Packet},
+    peer::Peer,
+    rotation::rotate_files,
+};
+use log::{debug, info, warn};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Error, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Link type for packets that start with an IP header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65_535;
+
+struct PcapWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+    ip_id: u16,
+}
+
+fn pcap_writer() -> &'static Mutex<Option<PcapWriter>> {
+    static WRITER: OnceLock<Mutex<Option<PcapWriter>>> = OnceLock::new();
+    WRITER.get_or_init(|| Mutex::new(None))
+}
+
+/// Starts capturing to the given file, or the file from the config. A running capture is
+/// replaced. Returns the path we capture to.
+pub(crate) fn start(path: Option<&Path>, config: &PcapConfig) -> io::Result<PathBuf> {
+    let path = path.unwrap_or(&config.file).to_path_buf();
+    let writer = PcapWriter {
+        writer: create_file(&path)?,
+        path: path.clone(),
+        written: 0,
+        max_bytes: config.max_size_mb.max(1) * 1024 * 1024,
+        max_files: config.max_files,
+        ip_id: 0,
+    };
+    *lock()? = Some(writer);
+    info!("Capturing traffic to {:?}", path);
+    Ok(path)
+}
+
+/// Stops capturing. Returns whether a capture was running.
+pub(crate) fn stop() -> io::Result<bool> {
+    let writer = lock()?.take();
+    if let Some(mut writer) = writer {
+        writer.writer.flush()?;
+        info!("Stopped capturing traffic to {:?}", writer.path);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Captures a packet we received from `source` on our local address `destination`.
+pub(crate) fn received(bytes: &[u8], source: SocketAddr, destination: &str) {
+    let Some(destination) = parse_v4(destination) else {
+        return;
+    };
+    let Some(source) = v4(source) else {
+        return;
+    };
+    write(bytes, source, destination);
+}
+
+/// The address packets from `peer` arrive at. When we listen on all interfaces, this is the
+/// address of the interface that routes to the peer rather than `0.0.0.0`.
+pub(crate) fn local_address(peer: &Peer) -> String {
+    let configured = peer.local_addr();
+    match configured.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => route_to(&peer.remote_addr())
+            .map(|ip| SocketAddr::new(ip, addr.port()).to_string())
+            .unwrap_or(configured),
+        _ => configured,
+    }
+}
+
+/// Whether traffic is being captured, so callers can skip preparing a capture.
+pub(crate) fn capturing() -> bool {
+    matches!(pcap_writer().lock().as_deref(), Ok(Some(_)))
+}
+
+/// Captures a message we sent from `source` to `destination`.
+pub(crate) fn sent(message: &osc::Message, source: &str, destination: &str) {
+    let (Some(source), Some(destination)) = (parse_v4(source), parse_v4(destination)) else {
+        return;
+    };
+    match osc::encode(Packet::Message(message.clone())) {
+        Ok(bytes) => write(&bytes, source, destination),
+        Err(e) => debug!("Unable to encode message for capture: {:?}", e),
+    }
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> io::Result<std::sync::MutexGuard<'static, Option<PcapWriter>>> {
+    pcap_writer()
+        .lock()
+        .map_err(|_| Error::other("Pcap lock was poisoned."))
+}
+
+fn write(payload: &[u8], source: SocketAddrV4, destination: SocketAddrV4) {
+    let Ok(mut guard) = pcap_writer().lock() else {
+        return;
+    };
+    let Some(writer) = guard.as_mut() else {
+        return;
+    };
+    writer.ip_id = writer.ip_id.wrapping_add(1);
+    let packet = ipv4_udp_packet(payload, source, destination, writer.ip_id);
+    if let Err(e) = write_record(writer, &packet) {
+        warn!("Failed to write pcap record, capture stopped: {}", e);
+        *guard = None;
+    }
+}
+
+fn write_record(writer: &mut PcapWriter, packet: &[u8]) -> io::Result<()> {
+    let record_len = 16 + packet.len() as u64;
+    if writer.written + record_len > writer.max_bytes {
+        rotate(writer)?;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let w = &mut writer.writer;
+    w.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+    w.write_all(&now.subsec_micros().to_le_bytes())?;
+    w.write_all(&(packet.len() as u32).to_le_bytes())?;
+    w.write_all(&(packet.len() as u32).to_le_bytes())?;
+    w.write_all(packet)?;
+    w.flush()?;
+    writer.written += record_len;
+    Ok(())
+}
+
+/// Starts a new file after moving the current one out of the way.
+fn rotate(writer: &mut PcapWriter) -> io::Result<()> {
+    writer.writer.flush()?;
+    rotate_files(&writer.path, writer.max_files)?;
+    debug!("Rotated pcap file {:?}", writer.path);
+    writer.writer = create_file(&writer.path)?;
+    writer.written = 0;
+    Ok(())
+}
+
+fn create_file(path: &Path) -> io::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&4u16.to_le_bytes())?;
+    writer.write_all(&0i32.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&SNAPLEN.to_le_bytes())?;
+    writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+fn ipv4_udp_packet(
+    payload: &[u8],
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    id: u16,
+) -> Vec<u8> {
+    let udp_len = (8 + payload.len()) as u16;
+    let total_len = 20 + udp_len;
+
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[0x45, 0x00]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x40, 0x00, 64, 17, 0, 0]);
+    packet.extend_from_slice(&source.ip().octets());
+    packet.extend_from_slice(&destination.ip().octets());
+    let checksum = ipv4_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    // A UDP checksum of zero means no checksum, which is allowed for IPv4.
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Asks the OS which local address it would send from to reach `remote`. Connecting a
+/// UDP socket sends nothing.
+fn route_to(remote: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+fn parse_v4(addr: &str) -> Option<SocketAddrV4> {
+    addr.parse::<SocketAddr>().ok().and_then(v4)
+}
+
+fn v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(addr) => addr
+            .ip()
+            .to_ipv4_mapped()
+            .map(|ip: Ipv4Addr| SocketAddrV4::new(ip, addr.port())),
+    }
+}
@@ -10,6 +10,12 @@ pub(crate) struct Peer {
 
     pub remote_ip: String,   // What is this Peers ip address?
     pub remote_port: String, // Where do send packets to reach this Peer?
+    #[serde(default = "default_source_port")]
+    pub source_port: String, // Where do we send packets to this Peer from?
+}
+
+fn default_source_port() -> String {
+    String::from("53300")
 }
 
 impl Peer {
@@ -20,6 +26,10 @@ impl Peer {
     pub(crate) fn local_addr(&self) -> String {
         format!("{}:{}", self.local_ip, self.local_port)
     }
+
+    pub(crate) fn source_addr(&self) -> String {
+        format!("{}:{}", self.local_ip, self.source_port)
+    }
 }
 
 impl Display for Peer {
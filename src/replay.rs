@@ -0,0 +1,106 @@
+use crate::{capture::read_capture, config::Config, peer::PeerKind};
+use log::{debug, info};
+use std::{
+    io::{self, Error},
+    net::UdpSocket,
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
+
+/// Where replayed packets are sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReplayTarget {
+    /// Into the handlers, as if the packets came from the peers again.
+    Pipeline,
+    /// Straight to the instrument, only the packets the controller sent.
+    Instrument,
+    /// Straight to the controller, only the packets the instrument sent.
+    Controller,
+}
+
+impl FromStr for ReplayTarget {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pipeline" => Ok(ReplayTarget::Pipeline),
+            "instrument" => Ok(ReplayTarget::Instrument),
+            "controller" => Ok(ReplayTarget::Controller),
+            other => Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown replay target '{}'.", other),
+            )),
+        }
+    }
+}
+
+/// Slower replays than this would wait for days between packets.
+const MIN_SPEED: f64 = 0.01;
+
+/// Reads the replay speed from the command line.
+pub(crate) fn parse_speed(s: &str) -> Result<f64, String> {
+    let speed = s.parse::<f64>().map_err(|e| e.to_string())?;
+    match valid_speed(speed) {
+        true => Ok(speed),
+        false => Err(format!("Use 0, or a speed of at least {}.", MIN_SPEED)),
+    }
+}
+
+/// Sends the packets from a capture file with their original timing divided by `speed`.
+/// A speed of 0 sends all packets as fast as possible. Returns the number of packets sent.
+pub(crate) fn replay(
+    config: &Config,
+    path: &Path,
+    speed: f64,
+    target: ReplayTarget,
+) -> io::Result<usize> {
+    if !valid_speed(speed) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Replay speed {} is not 0 or at least {}.", speed, MIN_SPEED),
+        ));
+    }
+    let packets = read_capture(path)?;
+    info!(
+        "Replaying {} packets from {:?} to {:?}",
+        packets.len(),
+        path,
+        target
+    );
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let started = Instant::now();
+    let mut sent = 0;
+    for packet in &packets {
+        let destination = match (target, &packet.peer_kind) {
+            (ReplayTarget::Pipeline, PeerKind::Controller) => config.controller.local_addr(),
+            (ReplayTarget::Pipeline, PeerKind::Instrument) => config.instrument.local_addr(),
+            (ReplayTarget::Instrument, PeerKind::Controller) => config.instrument.remote_addr(),
+            (ReplayTarget::Controller, PeerKind::Instrument) => config.controller.remote_addr(),
+            _ => continue,
+        };
+
+        if speed > 0.0 {
+            let due = packet.offset.div_f64(speed);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        debug!("Replaying {} bytes to {}", packet.bytes.len(), destination);
+        socket.send_to(&packet.bytes, destination)?;
+        sent += 1;
+    }
+
+    Ok(sent)
+}
+
+// ********
+// Helpers
+// ********
+
+/// 0 replays as fast as possible, anything else has to be a finite speed of at least
+/// `MIN_SPEED`, or the waits between packets overflow.
+fn valid_speed(speed: f64) -> bool {
+    speed == 0.0 || (speed.is_finite() && speed >= MIN_SPEED)
+}
@@ -0,0 +1,22 @@
+//! Size-limited output files, like pcap captures and the message journal, are rotated to
+//! numbered files next to them.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Moves `file` to `file.1`, `file.1` to `file.2` and so on, dropping the oldest file, so
+/// at most `max_files` files are kept.
+pub(crate) fn rotate_files(path: &Path, max_files: usize) -> io::Result<()> {
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.to_string_lossy(), n));
+    if max_files > 1 {
+        for n in (1..max_files - 1).rev() {
+            if numbered(n).exists() {
+                std::fs::rename(numbered(n), numbered(n + 1))?;
+            }
+        }
+        std::fs::rename(path, numbered(1))?;
+    }
+    Ok(())
+}
@@ -1,28 +1,62 @@
-use crate::osc;
+use crate::{osc, pcap, stats};
 use std::{
+    collections::HashMap,
     io::{self, Error, ErrorKind},
-    sync::Arc,
+    sync::{Arc, Mutex, OnceLock},
 };
 
 use log::debug;
 
 use crate::peer::Peer;
 
+/// One socket per source address, shared by every thread that sends, so peers always see
+/// packets coming from the configured source port.
+fn senders() -> &'static Mutex<HashMap<String, Arc<osc::Sender>>> {
+    static SENDERS: OnceLock<Mutex<HashMap<String, Arc<osc::Sender>>>> = OnceLock::new();
+    SENDERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
 pub(crate) fn send_message(message: osc::Message, peer_send: Arc<Peer>) -> Result<(), io::Error> {
-    // let port = thread_rng().gen_range(8000..50_000);
-    let port = 53300;
-    let bind_addr = format!("{}:{}", peer_send.local_ip, port);
+    send_from_source(message, &peer_send).inspect_err(|_| stats::send_error(&peer_send.kind))
+}
+
+// ********
+// Helpers
+// ********
+
+fn send_from_source(message: osc::Message, peer_send: &Peer) -> Result<(), io::Error> {
+    let bind_addr = peer_send.source_addr();
+    let sender = {
+        let mut senders = senders()
+            .lock()
+            .map_err(|_| Error::other("Sender lock was poisoned."))?;
+        match senders.get(&bind_addr) {
+            Some(sender) => sender.clone(),
+            None => {
+                let sender = Arc::new(osc::sender(bind_addr.clone())?);
+                senders.insert(bind_addr.clone(), sender.clone());
+                sender
+            }
+        }
+    };
     debug!(
         "Sending message to {} from {}\n {:?}",
         peer_send, bind_addr, message
     );
 
-    let sender = osc::sender(bind_addr)?;
+    // Only what actually went out ends up in the capture.
+    let captured = pcap::capturing().then(|| message.clone());
     match sender.send(message, peer_send.remote_addr()) {
-        Ok(_) => Ok(()),
+        Ok(bytes) => {
+            if let Some(message) = captured {
+                pcap::sent(&message, &bind_addr, &peer_send.remote_addr());
+            }
+            stats::message_sent(&peer_send.kind, bytes);
+            Ok(())
+        }
         Err(e) => Err(Error::new(
             ErrorKind::Interrupted,
-            format!("Error sending message: {}", e.to_string()),
+            format!("Error sending message: {}", e),
         )),
     }
 }
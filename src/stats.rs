@@ -0,0 +1,278 @@
+//! Counters for the traffic arcflash handles, readable over OSC with /sys/q/stats and
+//! written to the log on shutdown.
+
+use crate::{journal, peer::PeerKind};
+use log::info;
+use rosc::OscType;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Processing times of this many of the latest messages are kept for the percentiles.
+const LATENCY_WINDOW: usize = 1024;
+
+/// Traffic to and from a single peer.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PeerStats {
+    pub packets_in: u64,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    /// Messages from this peer dropped by filter rules.
+    pub filtered: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    pub decode_errors: u64,
+    pub send_errors: u64,
+}
+
+/// How long handling a message took, from decoding to sending the results.
+#[derive(Debug, Clone)]
+pub(crate) struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// A copy of all counters at one moment.
+#[derive(Debug, Clone)]
+pub(crate) struct Snapshot {
+    pub uptime: Duration,
+    pub controller: PeerStats,
+    pub instrument: PeerStats,
+    pub extensions: BTreeMap<&'static str, u64>,
+    pub extension_time: BTreeMap<&'static str, Duration>,
+    pub processed: u64,
+    pub processing_time: Duration,
+    pub latency: Option<Percentiles>,
+}
+
+struct Stats {
+    started: Instant,
+    controller: PeerStats,
+    instrument: PeerStats,
+    extensions: BTreeMap<&'static str, u64>,
+    extension_time: BTreeMap<&'static str, Duration>,
+    processed: u64,
+    processing_time: Duration,
+    latencies: VecDeque<Duration>,
+}
+
+fn stats() -> &'static Mutex<Stats> {
+    static STATS: OnceLock<Mutex<Stats>> = OnceLock::new();
+    STATS.get_or_init(|| {
+        Mutex::new(Stats {
+            started: Instant::now(),
+            controller: PeerStats::default(),
+            instrument: PeerStats::default(),
+            extensions: BTreeMap::new(),
+            extension_time: BTreeMap::new(),
+            processed: 0,
+            processing_time: Duration::ZERO,
+            latencies: VecDeque::with_capacity(LATENCY_WINDOW),
+        })
+    })
+}
+
+/// Counts a packet received from a peer.
+pub(crate) fn packet_received(kind: &PeerKind, bytes: usize) {
+    update_peer(kind, |peer| {
+        peer.packets_in += 1;
+        peer.bytes_in += bytes as u64;
+    });
+}
+
+/// Counts the messages in a decoded packet.
+pub(crate) fn messages_received(kind: &PeerKind, count: usize) {
+    update_peer(kind, |peer| peer.messages_in += count as u64);
+}
+
+pub(crate) fn filtered(kind: &PeerKind) {
+    update_peer(kind, |peer| peer.filtered += 1);
+}
+
+pub(crate) fn decode_error(kind: &PeerKind) {
+    update_peer(kind, |peer| peer.decode_errors += 1);
+}
+
+/// Counts a message sent to a peer.
+pub(crate) fn message_sent(kind: &PeerKind, bytes: usize) {
+    update_peer(kind, |peer| {
+        peer.messages_out += 1;
+        peer.bytes_out += bytes as u64;
+    });
+}
+
+pub(crate) fn send_error(kind: &PeerKind) {
+    update_peer(kind, |peer| peer.send_errors += 1);
+}
+
+/// Counts a message an extension acted on.
+pub(crate) fn extension_hit(name: &'static str) {
+    journal::extension_acted(name);
+    if let Some(mut stats) = lock() {
+        *stats.extensions.entry(name).or_default() += 1;
+    }
+}
+
+/// Runs an extension step and adds the time it took to the total for that extension.
+pub(crate) fn time_extension<T>(name: &'static str, step: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = step();
+    if let Some(mut stats) = lock() {
+        *stats.extension_time.entry(name).or_default() += started.elapsed();
+    }
+    result
+}
+
+/// Adds the time it took to handle a message to the latency window.
+pub(crate) fn processing_time(duration: Duration) {
+    if let Some(mut stats) = lock() {
+        stats.processed += 1;
+        stats.processing_time += duration;
+        if stats.latencies.len() == LATENCY_WINDOW {
+            stats.latencies.pop_front();
+        }
+        stats.latencies.push_back(duration);
+    }
+}
+
+pub(crate) fn packets_received(kind: &PeerKind) -> u64 {
+    lock()
+        .map(|stats| match kind {
+            PeerKind::Controller => stats.controller.packets_in,
+            PeerKind::Instrument => stats.instrument.packets_in,
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn snapshot() -> Option<Snapshot> {
+    let stats = lock()?;
+    let mut latencies: Vec<Duration> = stats.latencies.iter().copied().collect();
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    let latency = match latencies.is_empty() {
+        true => None,
+        false => Some(Percentiles {
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: percentile(100),
+        }),
+    };
+    Some(Snapshot {
+        uptime: stats.started.elapsed(),
+        controller: stats.controller.clone(),
+        instrument: stats.instrument.clone(),
+        extensions: stats.extensions.clone(),
+        extension_time: stats.extension_time.clone(),
+        processed: stats.processed,
+        processing_time: stats.processing_time,
+        latency,
+    })
+}
+
+/// All counters as OSC addresses and values, latencies in milliseconds.
+pub(crate) fn osc_report() -> Vec<(String, OscType)> {
+    let Some(snapshot) = snapshot() else {
+        return vec![];
+    };
+    let mut report = vec![(
+        String::from("/sys/stats/uptime"),
+        OscType::Float(snapshot.uptime.as_secs_f32()),
+    )];
+    for (name, peer) in [
+        ("controller", &snapshot.controller),
+        ("instrument", &snapshot.instrument),
+    ] {
+        report.extend(peer_counters(peer).into_iter().map(|(counter, value)| {
+            (format!("/sys/stats/{}/{}", name, counter), osc_count(value))
+        }));
+    }
+    for (name, hits) in &snapshot.extensions {
+        report.push((format!("/sys/stats/extension/{}", name), osc_count(*hits)));
+    }
+    if let Some(latency) = &snapshot.latency {
+        for (name, value) in latency_values(latency) {
+            report.push((
+                format!("/sys/stats/latency/{}", name),
+                OscType::Float(value.as_secs_f32() * 1000.0),
+            ));
+        }
+    }
+    report
+}
+
+/// Writes all counters to the log.
+pub(crate) fn log_summary() {
+    let Some(snapshot) = snapshot() else {
+        return;
+    };
+    info!("Statistics after {:?}:", snapshot.uptime);
+    for (name, peer) in [
+        ("Controller", &snapshot.controller),
+        ("Instrument", &snapshot.instrument),
+    ] {
+        let counters: Vec<String> = peer_counters(peer)
+            .into_iter()
+            .map(|(counter, value)| format!("{} {}", counter, value))
+            .collect();
+        info!("  {}: {}", name, counters.join(", "));
+    }
+    for (name, hits) in &snapshot.extensions {
+        info!("  Extension {}: {} messages", name, hits);
+    }
+    if let Some(latency) = &snapshot.latency {
+        let values: Vec<String> = latency_values(latency)
+            .into_iter()
+            .map(|(name, value)| format!("{} {:?}", name, value))
+            .collect();
+        info!("  Processing time: {}", values.join(", "));
+    }
+}
+
+// ********
+// Helpers
+// ********
+
+fn lock() -> Option<MutexGuard<'static, Stats>> {
+    stats().lock().ok()
+}
+
+fn update_peer(kind: &PeerKind, update: impl FnOnce(&mut PeerStats)) {
+    if let Some(mut stats) = lock() {
+        match kind {
+            PeerKind::Controller => update(&mut stats.controller),
+            PeerKind::Instrument => update(&mut stats.instrument),
+        }
+    }
+}
+
+/// Controllers rarely understand 64-bit integers, so counts are capped at `i32::MAX`.
+fn osc_count(count: u64) -> OscType {
+    OscType::Int(count.min(i32::MAX as u64) as i32)
+}
+
+fn peer_counters(peer: &PeerStats) -> [(&'static str, u64); 8] {
+    [
+        ("packets_in", peer.packets_in),
+        ("messages_in", peer.messages_in),
+        ("bytes_in", peer.bytes_in),
+        ("filtered", peer.filtered),
+        ("messages_out", peer.messages_out),
+        ("bytes_out", peer.bytes_out),
+        ("decode_errors", peer.decode_errors),
+        ("send_errors", peer.send_errors),
+    ]
+}
+
+fn latency_values(latency: &Percentiles) -> [(&'static str, Duration); 4] {
+    [
+        ("p50", latency.p50),
+        ("p90", latency.p90),
+        ("p99", latency.p99),
+        ("max", latency.max),
+    ]
+}
@@ -1,47 +1,1694 @@
-/*
-pub(crate) fn test_q_all_params() {
-    let config_file = PathBuf::from("./config.toml");
-    let config = Arc::new(read_config_from_file(&config_file).unwrap());
+//! Runs arcflash's handlers on loopback ports against mock peers. The mocks play the
+//! controller and the instrument: they send scripted OSC to arcflash and assert on what
+//! arcflash sends them.
+//!
+//! Every harness keeps its data in its own temporary directory and hands the handlers
+//! sockets it bound itself, so tests can run in parallel.
 
-    let config_ = config.clone();
+use crate::{
+    config::Config,
+    handler::spawn_handler_on,
+    osc::{self, Packet, Receiver},
+    peer::PeerKind,
+};
+use rosc::OscType;
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{Arc, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
 
-    let timer = async_std::task::sleep(std::time::Duration::from_secs(3));
-    let packet_counter_task = async_std::task::spawn(async move {
-        let mut packet_count = 0;
-        packet_counter(config.clone(), &mut packet_count).await;
-        println!()
-    });
+/// How long a mock waits for a message before the test fails.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a mock listens to be sure nothing arrives.
+const QUIET: Duration = Duration::from_millis(200);
 
-    async_std::task::spawn(async move { req_all_params(config_).await });
+/// A controller or instrument that talks to arcflash over loopback.
+struct MockPeer {
+    socket: UdpSocket,
+    /// Where arcflash receives packets from this peer.
+    arcflash: String,
+}
+
+impl MockPeer {
+    fn bind(arcflash_port: u16) -> Self {
+        Self {
+            socket: UdpSocket::bind("127.0.0.1:0").expect("Unable to bind mock peer."),
+            arcflash: format!("127.0.0.1:{}", arcflash_port),
+        }
+    }
+
+    fn port(&self) -> u16 {
+        self.socket.local_addr().unwrap().port()
+    }
+
+    fn send(&self, addr: &str, args: Vec<OscType>) {
+        let bytes = osc::encode(Packet::Message(osc::msg(addr, args))).unwrap();
+        self.socket.send_to(&bytes, &self.arcflash).unwrap();
+    }
+
+    fn recv(&self, timeout: Duration) -> Option<osc::Message> {
+        self.recv_from(timeout).map(|(message, _)| message)
+    }
+
+    /// Receives a message and the address it was sent from.
+    fn recv_from(&self, timeout: Duration) -> Option<(osc::Message, SocketAddr)> {
+        self.socket.set_read_timeout(Some(timeout)).unwrap();
+        let mut buffer = [0; 4096];
+        let (len, source) = self.socket.recv_from(&mut buffer).ok()?;
+        let message = osc::decode(&buffer[..len])
+            .ok()?
+            .into_msgs()
+            .into_iter()
+            .next()?;
+        Some((message, source))
+    }
+
+    /// Waits for a message to the address, skipping any others.
+    fn expect(&self, addr: &str) -> osc::Message {
+        let started = Instant::now();
+        let mut skipped = vec![];
+        while let Some(remaining) = TIMEOUT.checked_sub(started.elapsed()) {
+            match self.recv(remaining) {
+                Some(message) if message.addr == addr => return message,
+                Some(message) => skipped.push(message.addr),
+                None => break,
+            }
+        }
+        panic!("No message to {} arrived, only {:?}", addr, skipped);
+    }
+
+    fn expect_args(&self, addr: &str, args: Vec<OscType>) {
+        assert_eq!(self.expect(addr).args, args, "Arguments of {}", addr);
+    }
+
+    fn expect_silence(&self) {
+        if let Some(message) = self.recv(QUIET) {
+            panic!("Expected nothing, received {:?}", message);
+        }
+    }
+}
+
+/// Handlers share arcflash's global state. Most tests can run side by side, tests of a
+/// global mode like learning run alone.
+static TURNS: RwLock<()> = RwLock::new(());
+
+/// Handlers for both peers, bound to free ports, and the mocks they talk to.
+struct Harness {
+    controller: MockPeer,
+    instrument: MockPeer,
+    config: Arc<Config>,
+    dir: TempDir,
+    _shared: Option<RwLockReadGuard<'static, ()>>,
+    _alone: Option<RwLockWriteGuard<'static, ()>>,
+}
+
+impl Harness {
+    fn start() -> Self {
+        Self::start_with(true, "")
+    }
+
+    /// Starts with extensions on or off and more config, like filter rules.
+    fn start_with(extend: bool, extra_config: &str) -> Self {
+        let shared = TURNS.read().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            _shared: Some(shared),
+            ..Self::start_handlers(extend, "", extra_config)
+        }
+    }
+
+    /// Starts once no other test is running and keeps them waiting until dropped.
+    fn start_alone() -> Self {
+        Self::start_alone_with("")
+    }
+
+    fn start_alone_with(extra_config: &str) -> Self {
+        let alone = TURNS.write().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            _alone: Some(alone),
+            ..Self::start_handlers(true, "", extra_config)
+        }
+    }
+
+    /// Starts with more settings in the `[options]` section, like a setlist file.
+    fn start_with_options(extra_options: &str) -> Self {
+        let shared = TURNS.read().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            _shared: Some(shared),
+            ..Self::start_handlers(true, extra_options, "")
+        }
+    }
+
+    fn start_handlers(extend: bool, extra_options: &str, extra_config: &str) -> Self {
+        let dir = tempfile::tempdir().expect("Unable to create temp dir.");
+        let (controller_socket, instrument_socket) = (local_socket(), local_socket());
+        let controller_port = controller_socket.local_addr().unwrap().port();
+        let instrument_port = instrument_socket.local_addr().unwrap().port();
+        let controller = MockPeer::bind(controller_port);
+        let instrument = MockPeer::bind(instrument_port);
+
+        let config = format!(
+            r#"
+            [options]
+            extend = {extend}
+            dryrun = false
+            patch_cache_path = "/patches/"
+            snapshot_path = "/snapshots/"
+            bindings_file = "/bindings.toml"
+            data_dir = {data_dir:?}
+            {extra_options}
+
+            [controller]
+            name = "Mock controller"
+            kind = "Controller"
+            local_ip = "127.0.0.1"
+            local_port = "{controller_port}"
+            remote_ip = "127.0.0.1"
+            remote_port = "{}"
+            source_port = "0"
+
+            [instrument]
+            name = "Mock instrument"
+            kind = "Instrument"
+            local_ip = "127.0.0.1"
+            local_port = "{instrument_port}"
+            remote_ip = "127.0.0.1"
+            remote_port = "{}"
+            source_port = "0"
+
+            {extra_config}
+            "#,
+            controller.port(),
+            instrument.port(),
+            data_dir = dir.path(),
+        );
+        let config: Arc<Config> = Arc::new(toml::from_str(&config).unwrap());
+        spawn_handler_on(
+            config.clone(),
+            PeerKind::Controller,
+            Receiver::from_socket(controller_socket, 1024),
+        );
+        spawn_handler_on(
+            config.clone(),
+            PeerKind::Instrument,
+            Receiver::from_socket(instrument_socket, 1024),
+        );
+
+        Self {
+            controller,
+            instrument,
+            config,
+            dir,
+            _shared: None,
+            _alone: None,
+        }
+    }
+
+    fn patchbay(&self, bay: &str) -> PathBuf {
+        self.dir.path().join("patches").join(bay)
+    }
+}
+
+/// A loopback socket on a port the OS picked.
+fn local_socket() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").expect("Unable to bind handler socket.")
+}
+
+fn string(s: &str) -> OscType {
+    OscType::String(String::from(s))
+}
+
+// ********
+// Pass-through
+// ********
+
+#[test]
+fn passes_parameters_through_both_ways() {
+    let harness = Harness::start();
+    harness
+        .controller
+        .send("/param/a/osc/1/pitch", vec![OscType::Float(0.25)]);
+    harness
+        .instrument
+        .expect_args("/param/a/osc/1/pitch", vec![OscType::Float(0.25)]);
+
+    harness
+        .instrument
+        .send("/param/a/osc/1/pitch", vec![OscType::Float(0.75)]);
+    harness
+        .controller
+        .expect_args("/param/a/osc/1/pitch", vec![OscType::Float(0.75)]);
+}
+
+#[test]
+fn passes_everything_through_without_extensions() {
+    let harness = Harness::start_with(false, "");
+    harness.controller.send("/sys/q/arcflash", vec![]);
+    harness.instrument.expect_args("/sys/q/arcflash", vec![]);
+    harness.controller.expect_silence();
+
+    harness
+        .instrument
+        .send("/param/a/filter/1/type", vec![OscType::Int(2)]);
+    harness
+        .controller
+        .expect_args("/param/a/filter/1/type", vec![OscType::Int(2)]);
+}
+
+#[test]
+fn sends_from_the_source_port_from_every_thread() {
+    use crate::sender::send_message;
+    let harness = Harness::start();
+    harness
+        .controller
+        .send("/param/p/amp/pan", vec![OscType::Float(0.5)]);
+    let (_, handler_source) = harness.instrument.recv_from(TIMEOUT).unwrap();
+
+    // Features like the looper send from threads of their own.
+    let instrument = Arc::new(harness.config.instrument.clone());
+    std::thread::spawn(move || send_message(osc::msg("/param/p/amp/pan", vec![]), instrument))
+        .join()
+        .unwrap()
+        .unwrap();
+    let (_, thread_source) = harness.instrument.recv_from(TIMEOUT).unwrap();
+    assert_eq!(handler_source, thread_source);
+
+    let source_port = local_socket().local_addr().unwrap().port();
+    let mut instrument = harness.config.instrument.clone();
+    instrument.source_port = source_port.to_string();
+    send_message(osc::msg("/param/p/amp/pan", vec![]), Arc::new(instrument)).unwrap();
+    let (_, source) = harness.instrument.recv_from(TIMEOUT).unwrap();
+    assert_eq!(source.port(), source_port);
+}
+
+// ********
+// Type lookups and normalized strings
+// ********
+
+#[test]
+fn translates_filter_types() {
+    let harness = Harness::start();
+    harness
+        .instrument
+        .send("/param/a/filter/1/type", vec![OscType::Int(2)]);
+    harness
+        .controller
+        .expect_args("/param/a/filter/1/type", vec![string("LP 24 dB")]);
+
+    harness
+        .controller
+        .send("/param/b/filter/2/type", vec![string("LP 12 dB")]);
+    harness
+        .instrument
+        .expect_args("/param/b/filter/2/type", vec![OscType::Int(1)]);
+}
+
+#[test]
+fn translates_fx_types() {
+    let harness = Harness::start();
+    harness
+        .instrument
+        .send("/param/fx/a/1/type", vec![OscType::Float(1.0)]);
+    harness
+        .controller
+        .expect_args("/param/fx/a/1/type", vec![string("Delay")]);
+
+    harness
+        .controller
+        .send("/param/fx/b/2/type", vec![string("Phaser")]);
+    harness
+        .instrument
+        .expect_args("/param/fx/b/2/type", vec![OscType::Int(3)]);
+}
+
+#[test]
+fn passes_unknown_types_unchanged() {
+    let harness = Harness::start();
+    harness
+        .controller
+        .send("/param/a/filter/1/type", vec![string("Not a filter")]);
+    harness
+        .instrument
+        .expect_args("/param/a/filter/1/type", vec![string("Not a filter")]);
+}
+
+#[test]
+fn normalizes_value_strings_for_the_controller() {
+    let harness = Harness::start();
+    harness.instrument.send(
+        "/param/a/filter/1/cutoff",
+        vec![string("440.00 Hz 0.5 (normalized)")],
+    );
+    harness
+        .controller
+        .expect_args("/param/a/filter/1/cutoff", vec![OscType::Float(0.5)]);
+
+    // Only strings with a normalized value are touched.
+    harness
+        .instrument
+        .send("/param/a/filter/1/cutoff", vec![string("440.00 Hz")]);
+    harness
+        .controller
+        .expect_args("/param/a/filter/1/cutoff", vec![string("440.00 Hz")]);
+}
+
+// ********
+// System replies
+// ********
+
+#[test]
+fn answers_system_queries_to_the_sender() {
+    let harness = Harness::start();
+    harness.controller.send("/sys/q/arcflash", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/arcflash", vec![OscType::Bool(true)]);
+
+    harness.controller.send("/sys/q/stats", vec![]);
+    harness.controller.expect("/sys/stats/uptime");
+
+    harness.instrument.send("/sys/ping", vec![OscType::Int(7)]);
+    harness
+        .instrument
+        .expect_args("/sys/pong", vec![OscType::Int(7)]);
+
+    harness.controller.send("/sys/not/a/thing", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/debug", vec![string("Unknown address.")]);
+    harness.instrument.expect_silence();
+}
+
+#[test]
+fn counts_traffic_in_the_stats() {
+    let harness = Harness::start();
+    let counter = |name: &str| {
+        harness.controller.send("/sys/q/stats", vec![]);
+        let addr = format!("/sys/stats/controller/{}", name);
+        let count = match harness.controller.expect(&addr).args[..] {
+            [OscType::Int(count)] => count,
+            ref args => panic!("{} sent {:?}", addr, args),
+        };
+        // Read the rest of the report so the next query starts fresh.
+        harness
+            .controller
+            .expect("/sys/stats/instrument/packets_in");
+        count
+    };
+
+    // Other tests add to the same counters, so they only have to grow enough.
+    let packets = counter("packets_in");
+    for _ in 0..3 {
+        harness.controller.send("/param/c/amp/gain", vec![]);
+    }
+    harness.instrument.expect("/param/c/amp/gain");
+    assert!(counter("packets_in") >= packets + 4);
+
+    let errors = counter("decode_errors");
+    harness
+        .controller
+        .socket
+        .send_to(b"not osc", &harness.controller.arcflash)
+        .unwrap();
+    let started = Instant::now();
+    while counter("decode_errors") == errors {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "The decode error wasn't counted."
+        );
+    }
+}
+
+// ********
+// Patchbays
+// ********
+
+#[test]
+fn saves_and_loads_a_patchbay() {
+    let harness = Harness::start();
+    let bay = harness.patchbay("3");
+
+    harness
+        .controller
+        .send("/sys/patchbay/check", vec![string("3")]);
+    harness
+        .controller
+        .expect_args("/sys/patchbay/check/3", vec![OscType::Bool(false)]);
+
+    harness.controller.send(
+        "/sys/patchbay/save",
+        vec![string("3"), string("/Surge/patches/Bright Pad.fxp")],
+    );
+    let path = bay.join("Bright Pad.fxp");
+    harness
+        .instrument
+        .expect_args("/patch/save", vec![string(&path.to_string_lossy())]);
+    assert!(bay.join("tuning.toml").exists());
+
+    // The instrument saves the patch where it was asked to, and loads it without extension.
+    std::fs::write(&path, b"patch").unwrap();
+    let path = path.with_extension("");
+    harness
+        .controller
+        .send("/sys/patchbay/check", vec![string("3")]);
+    harness
+        .controller
+        .expect_args("/sys/patchbay/check/3", vec![OscType::Bool(true)]);
+
+    harness
+        .controller
+        .send("/sys/patchbay/load", vec![string("3")]);
+    harness
+        .instrument
+        .expect_args("/patch/load", vec![string(&path.to_string_lossy())]);
+    // The bay was saved in standard tuning, which is restored with it.
+    harness
+        .instrument
+        .expect_args("/tuning/scl", vec![string("")]);
+    harness.controller.expect_args(
+        "/sys/tuning/current",
+        vec![string(""), string("Standard tuning")],
+    );
+}
+
+#[test]
+fn saving_a_patchbay_replaces_its_patch() {
+    let harness = Harness::start();
+    let bay = harness.patchbay("1");
+    std::fs::create_dir_all(&bay).unwrap();
+    std::fs::write(bay.join("Old.fxp"), b"patch").unwrap();
+
+    harness
+        .controller
+        .send("/sys/patchbay/save", vec![string("1"), string("New")]);
+    harness.instrument.expect("/patch/save");
+    assert!(!bay.join("Old.fxp").exists());
+}
+
+#[test]
+fn loading_an_empty_patchbay_sends_nothing() {
+    let harness = Harness::start();
+    harness
+        .controller
+        .send("/sys/patchbay/load", vec![string("9")]);
+    harness.instrument.expect_silence();
+}
+
+#[test]
+fn exports_and_imports_patchbays() {
+    let harness = Harness::start();
+    let bay = harness.patchbay("2");
+    std::fs::create_dir_all(&bay).unwrap();
+    std::fs::write(bay.join("Lead.fxp"), b"patch").unwrap();
+    let archive = "bays.tar";
+
+    harness
+        .controller
+        .send("/sys/patchbay/export", vec![string(archive)]);
+    harness.controller.expect_args(
+        "/sys/patchbay/export",
+        vec![string("Exported 1 patchbays.")],
+    );
+    assert!(harness
+        .dir
+        .path()
+        .join("Arcflash/exchange/bays.tar")
+        .exists());
+
+    std::fs::remove_dir_all(&bay).unwrap();
+    harness.controller.send(
+        "/sys/patchbay/import",
+        vec![string(archive), string("replace")],
+    );
+    harness.controller.expect_args(
+        "/sys/patchbay/import",
+        vec![string("Imported 1 patchbays.")],
+    );
+    assert!(bay.join("Lead.fxp").exists());
+
+    harness.controller.send(
+        "/sys/patchbay/import",
+        vec![string(archive), string("overwrite")],
+    );
+    harness.controller.expect_args(
+        "/sys/patchbay/import",
+        vec![string(
+            "Error: Unknown import mode 'overwrite', use merge or replace.",
+        )],
+    );
+}
+
+#[test]
+fn refuses_file_names_outside_the_exchange_directory() {
+    let harness = Harness::start();
+    let outside = harness.dir.path().join("outside.tar");
+    for name in [
+        outside.to_string_lossy().to_string(),
+        String::from("../outside.tar"),
+    ] {
+        harness
+            .controller
+            .send("/sys/patchbay/export", vec![string(&name)]);
+        let status = harness.controller.expect("/sys/patchbay/export").args;
+        assert!(
+            matches!(&status[..], [OscType::String(s)] if s.starts_with("Error")),
+            "Exported to {}: {:?}",
+            name,
+            status
+        );
+        harness
+            .controller
+            .send("/sys/capture/start", vec![string(&name)]);
+        let status = harness.controller.expect("/sys/capture").args;
+        assert!(
+            matches!(&status[..], [OscType::String(s)] if s.starts_with("Error")),
+            "Captured to {}: {:?}",
+            name,
+            status
+        );
+    }
+    assert!(!outside.exists());
+    assert!(!harness.dir.path().join("Arcflash/outside.tar").exists());
+}
+
+#[test]
+fn plays_notes_only_with_a_notes_section() {
+    {
+        let harness = Harness::start();
+        harness
+            .controller
+            .send("/notes", vec![OscType::Float(60.0), OscType::Float(1.0)]);
+        harness
+            .instrument
+            .expect_args("/notes", vec![OscType::Float(60.0), OscType::Float(1.0)]);
+    }
+
+    // The note settings are shared by all handlers, so they are set over OSC.
+    let harness = Harness::start_with(true, "[notes]");
+    for (setting, value) in [("scale", "major"), ("key", "C"), ("chord", "off")] {
+        let addr = format!("/sys/notes/{}", setting);
+        harness.controller.send(&addr, vec![string(value)]);
+        harness.controller.expect_args(&addr, vec![string(value)]);
+    }
+    harness
+        .controller
+        .send("/sys/notes/octave", vec![OscType::Int(0)]);
+    harness
+        .controller
+        .expect_args("/sys/notes/octave", vec![OscType::Int(0)]);
+    harness
+        .controller
+        .send("/notes/61", vec![OscType::Float(1.0)]);
+    harness
+        .instrument
+        .expect_args("/mnote", vec![OscType::Float(60.0), OscType::Float(127.0)]);
+    harness
+        .controller
+        .send("/notes/61", vec![OscType::Float(0.0)]);
+    harness
+        .instrument
+        .expect_args("/mnote", vec![OscType::Float(60.0), OscType::Float(0.0)]);
+}
+
+// ********
+// Snapshots
+// ********
+
+#[test]
+fn saves_and_loads_a_snapshot() {
+    let harness = Harness::start();
+    harness
+        .instrument
+        .send("/param/s/amp/gain", vec![OscType::Float(0.7)]);
+    harness.controller.expect("/param/s/amp/gain");
+
+    harness
+        .controller
+        .send("/sys/snapshot/save", vec![string("set")]);
+    harness.controller.expect("/sys/snapshot/save");
+    let snapshot = harness.dir.path().join("snapshots").join("set.toml");
+    let contents = std::fs::read_to_string(snapshot).unwrap();
+    assert!(
+        contents.contains("\"/param/s/amp/gain\" = 0.7"),
+        "{}",
+        contents
+    );
+
+    harness
+        .controller
+        .send("/sys/snapshot/check", vec![string("set")]);
+    harness
+        .controller
+        .expect_args("/sys/snapshot/check/set", vec![OscType::Bool(true)]);
+
+    harness
+        .controller
+        .send("/sys/snapshot/load", vec![string("set")]);
+    harness
+        .instrument
+        .expect_args("/param/s/amp/gain", vec![OscType::Float(0.7)]);
+    harness.controller.expect("/sys/snapshot/load");
+}
+
+#[test]
+fn refuses_snapshot_names_outside_the_snapshot_directory() {
+    let harness = Harness::start();
+    harness
+        .controller
+        .send("/sys/snapshot/save", vec![string("../escaped")]);
+    harness.controller.expect_silence();
+    assert!(!harness.dir.path().join("escaped.toml").exists());
+}
+
+// ********
+// Undo
+// ********
+
+#[test]
+fn undoes_and_redoes_controller_moves() {
+    // The undo journal takes every controller move, so other tests wait.
+    let harness = Harness::start_alone();
+    let pan = |value: f32| vec![OscType::Float(value)];
+    harness.instrument.send("/param/u/amp/pan", pan(0.1));
+    harness.controller.expect_args("/param/u/amp/pan", pan(0.1));
+
+    // Quick moves are one step.
+    for value in [0.5, 0.6] {
+        harness.controller.send("/param/u/amp/pan", pan(value));
+        harness
+            .instrument
+            .expect_args("/param/u/amp/pan", pan(value));
+    }
+    std::thread::sleep(Duration::from_millis(800));
+    harness.controller.send("/param/u/amp/pan", pan(0.9));
+    harness.instrument.expect_args("/param/u/amp/pan", pan(0.9));
+
+    for (command, value) in [("/sys/undo", 0.6), ("/sys/undo", 0.1), ("/sys/redo", 0.6)] {
+        harness.controller.send(command, vec![]);
+        harness
+            .instrument
+            .expect_args("/param/u/amp/pan", pan(value));
+        harness
+            .controller
+            .expect_args("/param/u/amp/pan", pan(value));
+        harness
+            .controller
+            .expect_args(command, vec![string("/param/u/amp/pan")]);
+    }
+    harness.controller.send("/sys/redo", vec![]);
+    harness.instrument.expect_args("/param/u/amp/pan", pan(0.9));
+    harness.controller.expect("/sys/redo");
+    harness.controller.send("/sys/redo", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/redo", vec![string("Nothing to redo.")]);
+}
+
+// ********
+// Macros
+// ********
+
+#[test]
+fn spreads_a_macro_over_its_targets() {
+    let harness = Harness::start_with(
+        true,
+        r#"
+        [[macro]]
+        name = "tone"
+        address = "/macro/tone"
+        [[macro.targets]]
+        param = "/param/m/filter/1/cutoff"
+        curve = "logarithmic"
+        [[macro.targets]]
+        param = "/param/m/filter/1/resonance"
+        max = 0.5
+        curve = "exponential"
+        invert = true
+        "#,
+    );
+
+    harness
+        .controller
+        .send("/macro/tone", vec![OscType::Float(0.25)]);
+    harness
+        .instrument
+        .expect_args("/param/m/filter/1/cutoff", vec![OscType::Float(0.5)]);
+    harness
+        .instrument
+        .expect_args("/param/m/filter/1/resonance", vec![OscType::Float(0.46875)]);
+    harness
+        .controller
+        .expect_args("/sys/macro/tone", vec![OscType::Float(0.25)]);
+
+    // Positions are clamped, and integers work too.
+    harness
+        .controller
+        .send("/macro/tone", vec![OscType::Int(2)]);
+    harness
+        .instrument
+        .expect_args("/param/m/filter/1/cutoff", vec![OscType::Float(1.0)]);
+    harness
+        .controller
+        .expect_args("/sys/macro/tone", vec![OscType::Float(1.0)]);
+}
+
+// ********
+// Looper
+// ********
+
+#[test]
+fn records_and_plays_a_loop() {
+    let harness = Harness::start();
+    let expect_state = |state: &str| {
+        harness
+            .controller
+            .expect_args("/sys/loop/wobble/state", vec![string(state)]);
+    };
+    harness.controller.send("/sys/loop/wobble/record", vec![]);
+    expect_state("recording");
+    harness
+        .controller
+        .send("/param/l/amp/pan", vec![OscType::Float(0.3)]);
+    harness
+        .instrument
+        .expect_args("/param/l/amp/pan", vec![OscType::Float(0.3)]);
+    std::thread::sleep(Duration::from_millis(100));
+
+    harness.controller.send("/sys/loop/wobble/play", vec![]);
+    expect_state("playing");
+    for _ in 0..2 {
+        harness
+            .instrument
+            .expect_args("/param/l/amp/pan", vec![OscType::Float(0.3)]);
+    }
+
+    harness.controller.send("/sys/loop/wobble/stop", vec![]);
+    expect_state("stopped");
+    // Events sent before the stop are already waiting, nothing may follow them.
+    while harness.instrument.recv(Duration::from_millis(1)).is_some() {}
+    harness.instrument.expect_silence();
+
+    harness.controller.send("/sys/loop/wobble/clear", vec![]);
+    expect_state("empty");
+}
+
+// ********
+// Modulators
+// ********
+
+#[test]
+fn switches_a_modulator_on_and_off() {
+    let harness = Harness::start_with(
+        true,
+        r#"
+        [[modulator]]
+        name = "sway"
+        param = "/param/w/amp/pan"
+        shape = "triangle"
+        rate = 1.0
+        depth = 0.25
+        center = 0.5
+        "#,
+    );
+    crate::extension::system::modulator::start(harness.config.clone());
+
+    harness.controller.send("/sys/mod/sway/on", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/mod/sway/on", vec![OscType::Bool(true)]);
+    let value = osc::as_f32(&harness.instrument.expect("/param/w/amp/pan").args[0]).unwrap();
+    assert!(value > 0.5 && value <= 0.75, "Modulated to {}", value);
+
+    harness
+        .controller
+        .send("/sys/mod/sway/depth", vec![OscType::Float(0.125)]);
+    harness
+        .controller
+        .expect_args("/sys/mod/sway/depth", vec![OscType::Float(0.125)]);
+
+    // Switching off puts the parameter back at its center and stops the modulation.
+    harness.controller.send("/sys/mod/sway/on", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/mod/sway/on", vec![OscType::Bool(false)]);
+    let mut values = vec![];
+    while let Some(message) = harness.instrument.recv(QUIET) {
+        values.extend(message.args);
+    }
+    assert!(values.contains(&OscType::Float(0.5)), "{:?}", values);
+}
+
+// ********
+// Sequencer
+// ********
+
+#[test]
+fn plays_the_step_sequence() {
+    let harness = Harness::start();
+    let set = |addr: &str, value: OscType, reply: OscType| {
+        harness.controller.send(addr, vec![value]);
+        harness.controller.expect_args(addr, vec![reply]);
+    };
+    set("/sys/seq/length", OscType::Int(40), OscType::Int(32));
+    set("/sys/seq/length", OscType::Int(16), OscType::Int(16));
+    set(
+        "/sys/seq/tempo",
+        OscType::Float(600.0),
+        OscType::Float(600.0),
+    );
+    set(
+        "/sys/seq/step/2/on",
+        OscType::Bool(true),
+        OscType::Bool(true),
+    );
+    set("/sys/seq/step/2/note", OscType::Int(64), OscType::Int(64));
+    set(
+        "/sys/seq/step/2/velocity",
+        OscType::Int(200),
+        OscType::Int(127),
+    );
+
+    harness.controller.send("/sys/seq/play", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/seq/playing", vec![OscType::Bool(true)]);
+    harness
+        .controller
+        .expect_args("/sys/seq/playhead", vec![OscType::Int(1)]);
+    harness
+        .controller
+        .expect_args("/sys/seq/playhead", vec![OscType::Int(2)]);
+    let note = |velocity: f32| vec![OscType::Float(64.0), OscType::Float(velocity)];
+    harness.instrument.expect_args("/mnote", note(127.0));
+    harness.instrument.expect_args("/mnote", note(0.0));
+
+    harness.controller.send("/sys/seq/stop", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/seq/playing", vec![OscType::Bool(false)]);
+    harness.controller.send("/sys/seq/clear", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/seq/clear", vec![OscType::Bool(true)]);
+    harness.controller.send("/sys/seq/step/2/on", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/seq/step/2/on", vec![OscType::Bool(false)]);
+}
+
+#[test]
+fn ignores_sequencer_values_that_are_not_finite() {
+    // The sequencer is shared, so no other test may change a step in between.
+    let harness = Harness::start_alone();
+    let set = |addr: &str, value: f32, reply: OscType| {
+        harness.controller.send(addr, vec![OscType::Float(value)]);
+        harness.controller.expect_args(addr, vec![reply]);
+    };
+    set("/sys/seq/step/5/gate", 0.25, OscType::Float(0.25));
+    set("/sys/seq/step/5/gate", f32::NAN, OscType::Float(0.25));
+    set("/sys/seq/step/5/note", f32::INFINITY, OscType::Int(60));
+    set("/sys/seq/tempo", 300.0, OscType::Float(300.0));
+    set("/sys/seq/tempo", f32::INFINITY, OscType::Float(300.0));
+    set("/sys/seq/tempo", 1e9, OscType::Float(999.0));
+    set("/sys/seq/tempo", 120.0, OscType::Float(120.0));
+    harness.controller.send("/sys/seq/clear", vec![]);
+    harness.controller.expect("/sys/seq/clear");
+}
+
+// ********
+// Learn
+// ********
+
+#[test]
+fn learns_only_a_parameter_that_changed() {
+    // Any controller message would be learned, so other tests wait.
+    let harness = Harness::start_alone();
+    harness
+        .instrument
+        .send("/param/f/filter/1/cutoff", vec![OscType::Float(0.2)]);
+    harness
+        .controller
+        .expect_args("/param/f/filter/1/cutoff", vec![OscType::Float(0.2)]);
+
+    harness.controller.send("/sys/learn/start", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/learn/start", vec![OscType::Bool(true)]);
+    harness
+        .controller
+        .send("/fader/learned", vec![OscType::Float(0.7)]);
+    harness.instrument.expect("/fader/learned");
+
+    // Background traffic repeating the value doesn't bind.
+    harness
+        .instrument
+        .send("/param/f/filter/1/cutoff", vec![OscType::Float(0.2)]);
+    let message = harness.controller.recv(TIMEOUT).unwrap();
+    assert_eq!(message.addr, "/param/f/filter/1/cutoff");
+    harness.controller.expect_silence();
+
+    harness
+        .instrument
+        .send("/param/f/filter/1/cutoff", vec![OscType::Float(0.4)]);
+    harness.controller.expect_args(
+        "/sys/learn/bound",
+        vec![string("/fader/learned"), string("/param/f/filter/1/cutoff")],
+    );
+    harness
+        .controller
+        .send("/fader/learned", vec![OscType::Float(0.1)]);
+    harness
+        .instrument
+        .expect_args("/param/f/filter/1/cutoff", vec![OscType::Float(0.1)]);
+}
+
+// ********
+// OSC arguments
+// ********
+
+#[test]
+fn reads_numbers_from_any_numeric_argument() {
+    use crate::osc::{as_f32, as_i64};
+    assert_eq!(as_f32(&OscType::Int(3)), Some(3.0));
+    assert_eq!(as_f32(&OscType::Double(0.5)), Some(0.5));
+    assert_eq!(as_f32(&OscType::Bool(true)), Some(1.0));
+    assert_eq!(as_f32(&string("0.5")), None);
+    assert_eq!(as_i64(&OscType::Float(2.9)), Some(2));
+    assert_eq!(as_i64(&string("12")), Some(12));
+    assert_eq!(as_i64(&OscType::Nil), None);
+}
+
+// ********
+// Library
+// ********
+
+#[cfg(unix)]
+#[test]
+fn indexes_the_library_without_following_symlinked_folders() {
+    let library = tempfile::tempdir().unwrap();
+    let leads = library.path().join("Leads");
+    std::fs::create_dir_all(&leads).unwrap();
+    std::fs::write(leads.join("Saw.fxp"), b"patch").unwrap();
+    std::os::unix::fs::symlink(library.path(), leads.join("loop")).unwrap();
+    std::fs::write(leads.join("Pad.FXP"), b"patch").unwrap();
+    let harness = Harness::start_with(true, &format!("[library]\npaths = [{:?}]", library.path()));
+
+    harness.controller.send("/sys/library/rescan", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/library/rescan", vec![OscType::Int(2)]);
+}
+
+#[cfg(unix)]
+#[test]
+fn indexes_the_library_around_a_folder_it_cant_read() {
+    use std::os::unix::fs::PermissionsExt;
+    let library = tempfile::tempdir().unwrap();
+    for folder in ["Bass", "Locked"] {
+        std::fs::create_dir_all(library.path().join(folder)).unwrap();
+        std::fs::write(library.path().join(folder).join("Sub.fxp"), b"patch").unwrap();
+    }
+    let locked = library.path().join("Locked");
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+    // Root reads the folder anyway.
+    let readable = std::fs::read_dir(&locked).is_ok() as i32;
+    let harness = Harness::start_with(true, &format!("[library]\npaths = [{:?}]", library.path()));
+
+    harness.controller.send("/sys/library/rescan", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/library/rescan", vec![OscType::Int(1 + readable)]);
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+// ********
+// Tuning
+// ********
+
+#[cfg(unix)]
+#[test]
+fn indexes_tunings_without_following_symlinked_folders() {
+    let library = tempfile::tempdir().unwrap();
+    let just = library.path().join("Just");
+    std::fs::create_dir_all(&just).unwrap();
+    std::fs::write(
+        just.join("Just.scl"),
+        "! Just.scl\n!\nJust intonation\n 3\n!\n 9/8\n 5/4\n 2/1\n",
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(library.path(), just.join("loop")).unwrap();
+    // Patchbays save the current tuning, so none may be saved meanwhile.
+    let harness = Harness::start_alone_with(&format!("[tuning]\npaths = [{:?}]", library.path()));
+
+    harness.controller.send("/sys/tuning/list", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/tuning/count", vec![OscType::Int(1)]);
+
+    // Any kind of number picks a tuning.
+    harness
+        .controller
+        .send("/sys/tuning/load", vec![OscType::Long(1)]);
+    let path = just.join("Just");
+    harness
+        .instrument
+        .expect_args("/tuning/scl", vec![string(&path.to_string_lossy())]);
+    harness.controller.expect_args(
+        "/sys/tuning/current",
+        vec![string("Just"), string("Just intonation")],
+    );
+    harness.controller.send("/sys/tuning/reset", vec![]);
+    harness.controller.expect("/sys/tuning/current");
+}
+
+// ********
+// Setlist
+// ********
+
+#[test]
+fn steps_through_the_setlist() {
+    let songs = tempfile::tempdir().unwrap();
+    let setlist = songs.path().join("setlist.toml");
+    std::fs::write(
+        &setlist,
+        "[[song]]\ntitle = \"Intro\"\npatchbay = \"31\"\n\n\
+         [[song]]\ntitle = \"Outro\"\npatchbay = \"32\"\n",
+    )
+    .unwrap();
+    let harness = Harness::start_with_options(&format!("setlist_file = {:?}", setlist));
+
+    let expect_song = |title: &str, number: i32| {
+        harness
+            .controller
+            .expect_args("/sys/setlist/title", vec![string(title)]);
+        harness.controller.expect_args(
+            "/sys/setlist/position",
+            vec![OscType::Int(number), OscType::Int(2)],
+        );
+    };
+    // Any kind of number picks a song.
+    harness
+        .controller
+        .send("/sys/setlist/goto", vec![OscType::Long(1)]);
+    expect_song("Intro", 1);
+    for _ in 0..2 {
+        // Moving past the last song stays on it.
+        harness.controller.send("/sys/setlist/next", vec![]);
+        expect_song("Outro", 2);
+    }
+    harness.controller.send("/sys/setlist/prev", vec![]);
+    expect_song("Intro", 1);
+    harness
+        .controller
+        .send("/sys/setlist/goto", vec![OscType::Double(2.0)]);
+    expect_song("Outro", 2);
+}
+
+// ********
+// Address patterns
+// ********
+
+#[test]
+fn matches_osc_address_patterns() {
+    use crate::osc::{matches, AddressPattern};
+    assert!(matches("/sys/q/arcflash", "/sys/q/arcflash"));
+    assert!(!matches("/sys/q/arcflash", "/foo/sys/q/arcflash"));
+    assert!(!matches(
+        "/param/?/filter/?/type",
+        "/param/a/filter/1/type/extra"
+    ));
+    assert!(matches("/param/?/filter/?/type", "/param/b/filter/2/type"));
+    assert!(matches("/param/*/amp/*", "/param/a/amp/gain"));
+    assert!(!matches("/param/*/gain", "/param/a/amp/gain"));
+    assert!(matches(
+        "/param/[a-b]/osc/[!2-3]/pitch",
+        "/param/b/osc/1/pitch"
+    ));
+    assert!(!matches(
+        "/param/[a-b]/osc/[!2-3]/pitch",
+        "/param/b/osc/2/pitch"
+    ));
+    assert!(matches("/param/a/{cutoff,resonance}", "/param/a/resonance"));
+    assert!(!matches("/param/a/{cutoff,resonance}", "/param/a/res"));
+
+    assert!(matches("/param/a/*/cutoff", "/param/a/filter/cutoff"));
+    assert!(matches("/param/a/**/cutoff", "/param/a/filter/cutoff"));
+    assert!(!matches("/param/a/[12/cutoff", "/param/a/[12/cutoff"));
+
+    // Backtracking over many stars took exponential time on addresses that don't match.
+    let started = Instant::now();
+    let stars = format!("/param/{}b", "*a".repeat(40));
+    assert!(!matches(&stars, &format!("/param/{}", "a".repeat(200))));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    let long = format!(
+        "/param/{}",
+        "a".repeat(crate::osc::pattern::MAX_PATTERN_LEN)
+    );
+    assert!(!matches(&long, &long));
+    assert!(AddressPattern::new(&long).is_err());
+
+    assert!(AddressPattern::new("/param/{a,b/gain").is_err());
+    assert!(AddressPattern::new("/param/[a/b]").is_err());
+    assert!(AddressPattern::new("param/a").is_err());
+}
+
+#[test]
+fn expands_patterns_against_known_parameters() {
+    let harness = Harness::start();
+    for addr in ["/param/b/osc/3/pitch", "/param/b/osc/4/pitch"] {
+        harness.instrument.send(addr, vec![OscType::Float(0.5)]);
+        harness.controller.expect(addr);
+    }
+
+    harness
+        .controller
+        .send("/param/b/osc/[34]/pitch", vec![OscType::Float(0.25)]);
+    harness
+        .instrument
+        .expect_args("/param/b/osc/3/pitch", vec![OscType::Float(0.25)]);
+    harness
+        .instrument
+        .expect_args("/param/b/osc/4/pitch", vec![OscType::Float(0.25)]);
+
+    harness.controller.send("/q/param/b/osc/*/pitch", vec![]);
+    harness.instrument.expect("/q/param/b/osc/3/pitch");
+    harness.instrument.expect("/q/param/b/osc/4/pitch");
+}
+
+#[test]
+fn passes_unmatched_patterns_on_without_remembering_them() {
+    let harness = Harness::start();
+    // A remembered pattern would match itself the second time and expand forever.
+    for _ in 0..2 {
+        harness
+            .controller
+            .send("/param/zz/*/x", vec![OscType::Float(0.5)]);
+        harness
+            .instrument
+            .expect_args("/param/zz/*/x", vec![OscType::Float(0.5)]);
+    }
+    harness.controller.send("/sys/q/arcflash", vec![]);
+    harness
+        .controller
+        .expect_args("/sys/arcflash", vec![OscType::Bool(true)]);
+}
+
+#[test]
+fn ignores_system_addresses_outside_sys() {
+    let harness = Harness::start();
+    harness.controller.send("/foo/sys/q/arcflash", vec![]);
+    harness.controller.expect_silence();
+    harness.instrument.expect("/foo/sys/q/arcflash");
+}
+
+#[test]
+fn randomizes_parameters_matching_a_group_pattern() {
+    let harness = Harness::start_with(
+        true,
+        r#"
+        [[random_group]]
+        name = "osc d"
+        [[random_group.params]]
+        pattern = "/param/d/osc/[12]/pitch"
+        min = 0.2
+        max = 0.4
+        "#,
+    );
+    for addr in ["/param/d/osc/1/pitch", "/param/d/osc/3/pitch"] {
+        harness.instrument.send(addr, vec![OscType::Float(0.9)]);
+        harness.controller.expect(addr);
+    }
+
+    harness
+        .controller
+        .send("/sys/random", vec![string("osc d")]);
+    let randomized = harness.instrument.recv(TIMEOUT).unwrap();
+    assert_eq!(randomized.addr, "/param/d/osc/1/pitch");
+    let Some(OscType::Float(value)) = randomized.args.first() else {
+        panic!("Expected a float, got {:?}", randomized.args);
+    };
+    assert!((0.2..=0.4).contains(value));
+    harness.instrument.expect_silence();
 
-    loop {}
+    let group = "name = \"bad\"\n[[params]]\npattern = \"/param/{a\"";
+    assert!(toml::from_str::<crate::config::RandomGroup>(group).is_err());
 }
 
-async fn packet_counter(config: Arc<Config>, count: &mut usize) -> async_osc::Result<()> {
-    let mut socket = OscSocket::bind(config.instrument.local_addr()).await?;
-    // Listen for incoming packets on the socket.
-    while let Some(_) = socket.next().await {
-        *count += 1;
-        debug!("Received {count} messages.");
+#[test]
+fn randomizes_filter_types_by_name_and_reverts_them() {
+    // The values to revert to are shared with the other randomizer test.
+    let harness = Harness::start_alone_with(
+        r#"
+        [[random_group]]
+        name = "filter r"
+        [[random_group.params]]
+        pattern = "/param/r/filter/1/type"
+        min = 2.0
+        max = 2.0
+        amount = 1.0
+
+        [[random_group]]
+        name = "nothing"
+        [[random_group.params]]
+        pattern = "/param/r/nothing"
+        "#,
+    );
+    harness
+        .instrument
+        .send("/param/r/filter/1/type", vec![OscType::Float(1.0)]);
+    harness
+        .controller
+        .expect_args("/param/r/filter/1/type", vec![string("LP 12 dB")]);
+
+    harness
+        .controller
+        .send("/sys/random", vec![string("filter r")]);
+    harness
+        .instrument
+        .expect_args("/param/r/filter/1/type", vec![OscType::Float(2.0)]);
+    harness
+        .controller
+        .expect_args("/param/r/filter/1/type", vec![string("LP 24 dB")]);
+    harness.controller.expect("/sys/random");
+
+    harness
+        .controller
+        .send("/sys/random", vec![string("nothing")]);
+    harness
+        .controller
+        .expect_args("/sys/random", vec![string("Randomized 0 parameters.")]);
+    harness.controller.send("/sys/random/revert", vec![]);
+    harness
+        .instrument
+        .expect_args("/param/r/filter/1/type", vec![OscType::Float(1.0)]);
+    harness
+        .controller
+        .expect_args("/sys/random/revert", vec![string("Reverted 1 parameters.")]);
+}
+
+// ********
+// Filter rules
+// ********
+
+#[test]
+fn drops_messages_by_filter_rules() {
+    let harness = Harness::start_with(
+        false,
+        r#"
+        [[filter]]
+        action = "deny"
+        direction = "to_controller"
+        pattern = "/param/?/lfo/*/rate"
+
+        [[filter]]
+        action = "allow"
+        peer = "Mock controller"
+        regex = "^/param/a/"
+        "#,
+    );
+    harness
+        .instrument
+        .send("/param/b/lfo/2/rate", vec![OscType::Float(0.5)]);
+    harness
+        .instrument
+        .send("/param/b/lfo/2/rate/extra", vec![OscType::Float(0.5)]);
+    // Only the second message passes, so it is the first to arrive.
+    let arrived = harness.controller.recv(TIMEOUT).unwrap();
+    assert_eq!(arrived.addr, "/param/b/lfo/2/rate/extra");
+
+    harness
+        .controller
+        .send("/param/b/amp/gain", vec![OscType::Float(0.5)]);
+    harness
+        .controller
+        .send("/param/a/amp/gain", vec![OscType::Float(0.5)]);
+    let arrived = harness.instrument.recv(TIMEOUT).unwrap();
+    assert_eq!(arrived.addr, "/param/a/amp/gain");
+}
+
+#[test]
+fn filters_the_addresses_a_pattern_expands_to() {
+    let harness = Harness::start_with(
+        true,
+        r#"
+        [[filter]]
+        action = "deny"
+        direction = "to_instrument"
+        pattern = "/param/c/osc/1/pitch"
+        "#,
+    );
+    for addr in ["/param/c/osc/1/pitch", "/param/c/osc/2/pitch"] {
+        harness.instrument.send(addr, vec![OscType::Float(0.5)]);
+        harness.controller.expect(addr);
+    }
+
+    harness
+        .controller
+        .send("/param/c/osc/?/pitch", vec![OscType::Float(0.9)]);
+    let arrived = harness.instrument.recv(TIMEOUT).unwrap();
+    assert_eq!(arrived.addr, "/param/c/osc/2/pitch");
+    harness.instrument.expect_silence();
+}
+
+#[test]
+fn rejects_filters_without_addresses() {
+    let rule = "action = \"deny\"\ndirection = \"to_instrument\"";
+    assert!(toml::from_str::<crate::filter::FilterRule>(rule).is_err());
+    let rule = "action = \"deny\"\nregex = \"(\"";
+    assert!(toml::from_str::<crate::filter::FilterRule>(rule).is_err());
+}
+
+// ********
+// Replay
+// ********
+
+#[test]
+fn refuses_replay_speeds_that_overflow() {
+    use crate::replay::{parse_speed, replay, ReplayTarget};
+    assert_eq!(parse_speed("0"), Ok(0.0));
+    assert_eq!(parse_speed("0.5"), Ok(0.5));
+    for speed in ["0.001", "-1", "NaN", "inf"] {
+        assert!(parse_speed(speed).is_err(), "{} was accepted", speed);
+    }
+
+    let harness = Harness::start();
+    let missing = harness.dir.path().join("session.afcap");
+    let error = replay(&harness.config, &missing, 1e-300, ReplayTarget::Controller).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn reports_a_taken_port_instead_of_panicking() {
+    let harness = Harness::start();
+    // The harness handlers are bound to the configured ports already.
+    match crate::handler::bind_handler(&harness.config, &PeerKind::Controller) {
+        Ok(_) => panic!("Bound a port that was taken."),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse),
+    }
+}
+
+// ********
+// Pcap
+// ********
+
+#[test]
+fn captures_received_packets_at_a_real_address() {
+    use crate::{pcap, peer::Peer};
+    let peer = Peer {
+        name: String::from("controller"),
+        kind: PeerKind::Controller,
+        local_ip: String::from("0.0.0.0"),
+        local_port: String::from("53100"),
+        remote_ip: String::from("127.0.0.1"),
+        remote_port: String::from("53000"),
+        source_port: String::from("53300"),
+    };
+    assert_eq!(pcap::local_address(&peer), "127.0.0.1:53100");
+
+    let configured = Peer {
+        local_ip: String::from("127.0.0.2"),
+        ..peer
+    };
+    assert_eq!(pcap::local_address(&configured), "127.0.0.2:53100");
+}
+
+// ********
+// Monitor
+// ********
+
+/// What the handlers showed the monitor. It can only be started once, so the monitor
+/// tests share it and run alone to see only their own traffic.
+fn observations() -> &'static flume::Receiver<crate::monitor::Observation> {
+    static OBSERVATIONS: OnceLock<flume::Receiver<crate::monitor::Observation>> = OnceLock::new();
+    OBSERVATIONS.get_or_init(crate::monitor::start)
+}
+
+/// An empty monitor that only sees what happens from now on.
+fn start_watching() -> crate::monitor::Monitor {
+    // Anything left over came from tests that ran before.
+    observations().drain();
+    crate::monitor::Monitor::default()
+}
+
+/// Records what the handlers showed the monitor until they have been quiet for a while.
+fn watch(monitor: &mut crate::monitor::Monitor) {
+    let observations = observations();
+    while let Ok(observation) = observations.recv_timeout(QUIET) {
+        monitor.record(observation);
+    }
+}
+
+/// The lines of the monitor as the terminal would show them.
+fn render(monitor: &crate::monitor::Monitor) -> Vec<String> {
+    use ratatui::{backend::TestBackend, Terminal};
+    let mut terminal = Terminal::new(TestBackend::new(140, 8)).unwrap();
+    terminal.draw(|frame| monitor.draw(frame)).unwrap();
+    let buffer = terminal.backend().buffer();
+    (0..buffer.area.height)
+        .map(|y| {
+            (0..buffer.area.width)
+                .map(|x| buffer[(x, y)].symbol())
+                .collect::<String>()
+        })
+        .collect()
+}
+
+fn row<'a>(lines: &'a [String], direction: &str, addr: &str) -> &'a str {
+    lines
+        .iter()
+        .find(|line| line.starts_with(direction) && line.contains(&format!(" {} ", addr)))
+        .unwrap_or_else(|| panic!("No row for {} in {:#?}", addr, lines))
+}
+
+fn press(monitor: &mut crate::monitor::Monitor, keys: &str) -> bool {
+    use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    keys.chars()
+        .all(|c| monitor.key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)))
+}
+
+fn enter(monitor: &mut crate::monitor::Monitor) {
+    use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    monitor.key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+}
+
+#[test]
+fn shows_a_row_per_direction_and_address_with_what_the_extensions_did() {
+    let harness = Harness::start_alone_with(
+        r#"
+        [[filter]]
+        action = "deny"
+        direction = "to_instrument"
+        pattern = "/param/m/amp/gain"
+        "#,
+    );
+    let mut monitor = start_watching();
+
+    harness
+        .instrument
+        .send("/param/m/amp/gain", vec![string("0.500 (normalized)")]);
+    harness.controller.expect("/param/m/amp/gain");
+    for _ in 0..2 {
+        harness
+            .controller
+            .send("/param/m/amp/pan", vec![OscType::Float(0.25)]);
+        harness.instrument.expect("/param/m/amp/pan");
+    }
+    // The pattern only stands for the denied gain, so nothing is left of it.
+    harness
+        .controller
+        .send("/param/m/amp/g*", vec![OscType::Float(0.9)]);
+    harness.instrument.expect_silence();
+    watch(&mut monitor);
+
+    let lines = render(&monitor);
+    let normalized = row(&lines, "instrument → controller", "/param/m/amp/gain");
+    assert!(
+        normalized.contains("\"0.500 (normalized)\""),
+        "{}",
+        normalized
+    );
+    assert!(normalized.contains(" 0.500 "), "{}", normalized);
+    let passed = row(&lines, "controller → instrument", "/param/m/amp/pan");
+    assert!(passed.contains(" 0.250 "), "{}", passed);
+    assert!(passed.trim_end().ends_with(" 2"), "{}", passed);
+    let consumed = row(&lines, "controller → instrument", "/param/m/amp/g*");
+    assert!(consumed.contains("(consumed)"), "{}", consumed);
+    assert!(lines.last().unwrap().contains("3 addresses"));
+}
+
+#[test]
+fn pauses_filters_and_quits_on_keys() {
+    let harness = Harness::start_alone();
+    let mut monitor = start_watching();
+    let send = |addr: &str| {
+        harness.controller.send(addr, vec![OscType::Float(0.5)]);
+        harness.instrument.expect(addr);
+    };
+
+    send("/param/n/amp/gain");
+    watch(&mut monitor);
+    assert!(press(&mut monitor, "p"));
+    send("/param/n/amp/pan");
+    watch(&mut monitor);
+    let lines = render(&monitor);
+    assert!(
+        lines.last().unwrap().contains("1 addresses  |  PAUSED"),
+        "{:#?}",
+        lines
+    );
+    press(&mut monitor, " ");
+    assert!(render(&monitor).last().unwrap().contains("2 addresses"));
+
+    // The first slash opens the filter input.
+    press(&mut monitor, "//param/*/amp/gain");
+    enter(&mut monitor);
+    let lines = render(&monitor);
+    assert!(lines
+        .last()
+        .unwrap()
+        .contains("1 addresses matching /param/*/amp/gain"));
+    row(&lines, "controller → instrument", "/param/n/amp/gain");
+
+    // The input starts with the current filter, an invalid pattern keeps it.
+    press(&mut monitor, "/[");
+    assert!(render(&monitor)
+        .last()
+        .unwrap()
+        .contains("Filter (OSC address pattern): /param/*/amp/gain[_"));
+    enter(&mut monitor);
+    assert!(render(&monitor)
+        .last()
+        .unwrap()
+        .starts_with("Invalid filter"));
+    press(&mut monitor, "/");
+    enter(&mut monitor);
+    assert!(render(&monitor)
+        .last()
+        .unwrap()
+        .contains("matching /param/*/amp/gain"));
+
+    assert!(!press(&mut monitor, "q"));
+}
+
+// ********
+// Journal
+// ********
+
+#[test]
+fn journals_what_became_of_a_message() {
+    let harness = Harness::start();
+    let file = harness.dir.path().join("journal.jsonl");
+    crate::journal::start(Some(&file), &Default::default()).unwrap();
+    harness
+        .instrument
+        .send("/param/a/filter/2/type", vec![OscType::Int(3)]);
+    harness
+        .controller
+        .expect_args("/param/a/filter/2/type", vec![string("LP Legacy Ladder")]);
+
+    // The entry is recorded after the message is sent, give it a moment.
+    std::thread::sleep(QUIET);
+    assert!(crate::journal::stop().unwrap());
+
+    // Other tests journal to the same file while it is running.
+    let journal = std::fs::read_to_string(&file).unwrap();
+    let entry = journal
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|entry| entry["addr"] == "/param/a/filter/2/type")
+        .expect("No journal entry for the filter type.");
+    assert_eq!(entry["from"]["kind"], "Instrument");
+    assert_eq!(entry["to"]["name"], "Mock controller");
+    assert_eq!(entry["args"], serde_json::json!([3]));
+    assert_eq!(
+        entry["transformed"],
+        serde_json::json!([{
+            "to": {"kind": "Controller", "name": "Mock controller"},
+            "addr": "/param/a/filter/2/type",
+            "args": ["LP Legacy Ladder"],
+        }])
+    );
+    assert_eq!(entry["extensions"], serde_json::json!(["filter_type"]));
+    assert_eq!(entry["error"], serde_json::Value::Null);
+}
+
+#[test]
+fn rotates_output_files_keeping_the_newest() {
+    use crate::rotation::rotate_files;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("arcflash.jsonl");
+    for generation in ["first", "second", "third"] {
+        std::fs::write(&path, generation).unwrap();
+        rotate_files(&path, 3).unwrap();
     }
-    Ok(())
+    let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+    assert_eq!(read("arcflash.jsonl.1"), "third");
+    assert_eq!(read("arcflash.jsonl.2"), "second");
+    assert!(!dir.path().join("arcflash.jsonl.3").exists());
 }
 
-async fn req_all_params(config: Arc<Config>) -> async_osc::Result<()> {
-    println!("Requesting all params");
-    let message = OscMessage::new("/q/all_params", OscType::Float(0.0));
-    let peer_send = Arc::new(config.instrument.clone());
-    sender::send_message(message, peer_send).await;
+// ********
+// Latency
+// ********
 
-    Ok(())
+#[test]
+fn measures_the_instrument_by_querying_a_parameter() {
+    let harness = Harness::start_with(true, "[latency]\nprobe_param = \"/param/e/volume\"");
+    harness.controller.send("/sys/q/latency", vec![]);
+    harness.instrument.expect("/q/param/e/volume");
+    harness
+        .instrument
+        .send("/param/e/volume", vec![string("0.500 (normalized)")]);
+
+    // The answer is also a parameter value the controller should know about.
+    harness
+        .controller
+        .expect_args("/param/e/volume", vec![OscType::Float(0.5)]);
+    let report = harness.controller.expect("/sys/latency/instrument").args;
+    assert_eq!(
+        report.len(),
+        5,
+        "min, avg, max, jitter and lost: {:?}",
+        report
+    );
+}
+
+#[test]
+fn switches_the_latency_monitor_with_any_number() {
+    let harness = Harness::start_alone();
+    harness
+        .controller
+        .send("/sys/latency/monitor", vec![OscType::Long(1)]);
+    harness
+        .controller
+        .expect_args("/sys/latency/monitor", vec![OscType::Bool(true)]);
+    harness
+        .controller
+        .send("/sys/latency/monitor", vec![OscType::Double(0.0)]);
+    harness
+        .controller
+        .expect_args("/sys/latency/monitor", vec![OscType::Bool(false)]);
 }
 
-async fn random_patch() -> async_osc::Result<()> {
-    println!("Requesting random patch");
-    let socket = OscSocket::bind("192.168.1.110:53102").await?;
-    let message = OscMessage::new("/patch/random", OscType::Float(0.0));
+// ********
+// Metrics
+// ********
+
+#[test]
+fn serves_metrics_while_a_client_idles() {
+    let harness = Harness::start();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    crate::metrics::serve(harness.config.clone(), listener);
 
-    socket.send_to(message, "192.168.1.103:53100").await?;
-    Ok(())
+    let _idle = TcpStream::connect(addr).unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(TIMEOUT * 3)).unwrap();
+    client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
 }
- */